@@ -0,0 +1,617 @@
+// Interactive command-line debugger.
+//
+// Addresses and values are hexadecimal; an optional 0x prefix or h suffix
+// is accepted. Where an address is expected, a symbol (NAME or NAME+n, n
+// in hex too) may be given instead, and addresses are shown with their
+// symbols. An empty line repeats the previous command, `!n` re-runs entry n
+// of the history.
+use intel8080::debug::{Condition, StopReason, WatchKind};
+use intel8080::disasm::{self, Flow};
+use intel8080::memory::{Pattern, Snapshot};
+use intel8080::state::StateFormat;
+use intel8080::symbols::SymbolTable;
+use intel8080::{hexfile, Intel8080};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+
+// `continue` and `next` give up after this many instructions so a program
+// stuck in a loop with no breakpoint hands control back.
+const CONTINUE_LIMIT: usize = 10_000_000;
+
+// Instructions remembered for stepping backwards, unless changed with
+// `record`.
+const HISTORY_SIZE: usize = 100_000;
+
+// `find` lists at most this many matches.
+const FIND_LIMIT: usize = 64;
+
+const HELP: &str = "\
+s, step [n]            execute n instructions (default 1)
+n, next                step over CALL/RST
+c, continue            run until a breakpoint or HLT
+b, break [addr]        set a breakpoint, or list all breakpoints
+d, delete addr         remove a breakpoint
+watch [r|w|rw] range   stop after memory in range (addr or addr-end) is accessed
+unwatch [r|w|rw] range remove a watchpoint
+port [in|out|io] n     stop before IN/OUT on port n
+unport [in|out|io] n   remove a port breakpoint
+r, regs [format]       show registers (compact, table or json)
+bt, backtrace          show the call stack
+set reg value          set a register (a b c d e h l bc de hl sp pc flags)
+x addr [len]           dump memory
+w addr byte...         write bytes to memory
+find [range] pattern   search memory (bytes, 4-digit words, \"strings\", ??)
+fill range pattern     fill memory with a repeated pattern
+copy range dest        copy memory
+snap [name]            take a snapshot of memory
+cmp name [name2]       list bytes changed since a snapshot (or between two)
+l, list [addr] [n]     disassemble n instructions (default: around PC)
+rs, rstep [n]          step back n instructions (default 1)
+rwrite addr            step back to the last instruction that wrote addr
+record [n|off]         record n instructions for stepping back, or stop
+h, history             show command history
+q, quit                leave the debugger
+
+break, watch and port take an optional condition after 'if', such as
+  b 0100 if A == 0x20 && HL > 2400h
+  b 0100 if [HL] == 0          ([addr] is the byte at addr)";
+
+pub struct Debugger {
+    cpu: Intel8080,
+    symbols: SymbolTable,
+    history: Vec<String>,
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_suffix(['h', 'H']))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// Watch kinds are given as r/w/rw for memory and in/out/io for ports.
+fn parse_kind(word: &str) -> Option<WatchKind> {
+    match word {
+        "r" | "in" => Some(WatchKind::Read),
+        "w" | "out" => Some(WatchKind::Write),
+        "rw" | "io" => Some(WatchKind::ReadWrite),
+        _ => None,
+    }
+}
+
+// Split `args` at an `if` keyword and parse the condition after it.
+fn split_condition<'a>(args: &'a [&'a str]) -> Result<(&'a [&'a str], Option<Condition>), String> {
+    match args.iter().position(|a| *a == "if") {
+        Some(i) => {
+            let condition =
+                Condition::parse(&args[i + 1..].join(" ")).map_err(|e| e.to_string())?;
+            Ok((&args[..i], Some(condition)))
+        }
+        None => Ok((args, None)),
+    }
+}
+
+impl Debugger {
+    pub fn new(mut cpu: Intel8080, symbols: SymbolTable) -> Self {
+        cpu.record_history(HISTORY_SIZE);
+        cpu.track_calls();
+        Self {
+            cpu,
+            symbols,
+            history: Vec::new(),
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    // A symbol, or failing that a number.
+    fn address(&self, text: &str) -> Option<u16> {
+        self.symbols
+            .resolve_with(text, &parse_number)
+            .or_else(|| parse_number(text))
+    }
+
+    // start-end, when both halves are addresses on their own; otherwise a
+    // single address, which may be NAME-n. None for a reversed range.
+    fn range(&self, text: &str) -> Option<RangeInclusive<u16>> {
+        if let Some((start, end)) = text.split_once('-') {
+            if let (Some(start), Some(end)) = (self.address(start), self.address(end)) {
+                return (start <= end).then_some(start..=end);
+            }
+        }
+        self.address(text).map(|addr| addr..=addr)
+    }
+
+    // A listing line with operands named, preceded by a label line when a
+    // symbol is defined at the address.
+    fn format_line(&self, prefix: &str, addr: u16) -> String {
+        let instr = self.cpu.disassemble(addr);
+        let line = disasm::format_line_with(&instr, &|a| self.symbols.label(a));
+        match self.symbols.name_at(addr) {
+            Some(name) => format!("{}:\n{}{}", name, prefix, line),
+            None => format!("{}{}", prefix, line),
+        }
+    }
+
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        self.show_position();
+        loop {
+            print!("(i8080) ");
+            io::stdout().flush().ok();
+            let Some(Ok(line)) = lines.next() else {
+                break;
+            };
+            let line = match self.recall(line.trim()) {
+                Some(line) => line,
+                None => continue,
+            };
+            if !self.command(&line) {
+                break;
+            }
+        }
+    }
+
+    // Resolve history references. Empty input repeats the last command.
+    fn recall(&mut self, line: &str) -> Option<String> {
+        let line = if line.is_empty() {
+            self.history.last()?.clone()
+        } else if let Some(n) = line.strip_prefix('!') {
+            match n.parse::<usize>().ok().and_then(|n| self.history.get(n)) {
+                Some(line) => line.clone(),
+                None => {
+                    println!("no history entry {}", n);
+                    return None;
+                }
+            }
+        } else {
+            line.to_string()
+        };
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+
+    // Execute one command. Returns false when the user quits.
+    fn command(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let args = &words[1..];
+        match words[0] {
+            "s" | "step" => {
+                let count = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+                match self.execute(count) {
+                    Some(StopReason::StepLimit) | None => {}
+                    Some(reason) => println!("{}", reason),
+                }
+                self.show_position();
+            }
+            "n" | "next" => {
+                let instr = self.cpu.disassemble(self.cpu.state().pc);
+                if let Flow::Call(_) = instr.flow() {
+                    self.step_over(instr.next_addr());
+                } else if let Some(reason) = self.execute(1) {
+                    if reason != StopReason::StepLimit {
+                        println!("{}", reason);
+                    }
+                }
+                self.show_position();
+            }
+            "c" | "continue" => {
+                if let Some(reason) = self.execute(CONTINUE_LIMIT) {
+                    println!("{}", reason);
+                }
+                self.show_position();
+            }
+            "b" | "break" => self.set_breakpoint(args),
+            "d" | "delete" => match args.first().and_then(|a| self.address(a)) {
+                Some(addr) if self.cpu.breakpoints_mut().remove(addr) => {
+                    println!("deleted breakpoint at {:04X}", addr)
+                }
+                Some(addr) => println!("no breakpoint at {:04X}", addr),
+                None => println!("usage: delete addr"),
+            },
+            "watch" | "unwatch" => self.watch(args, words[0] == "watch"),
+            "port" | "unport" => self.port(args, words[0] == "port"),
+            "r" | "regs" => match args.first().map(|a| StateFormat::from_name(a)) {
+                None => println!("{}", self.cpu.state()),
+                Some(Some(format)) => println!("{}", self.cpu.format_state(format)),
+                Some(None) => println!("usage: regs [compact|table|json]"),
+            },
+            "bt" | "backtrace" => self.backtrace(),
+            "set" => self.set_register(args),
+            "x" => self.examine(args),
+            "w" => self.write_memory(args),
+            "find" => self.find(args),
+            "fill" => self.fill(args),
+            "copy" => match (args.first().and_then(|a| self.range(a)), args.get(1)) {
+                (Some(src), Some(dest)) => match self.address(dest) {
+                    Some(dest) => {
+                        if let Err(err) = self.cpu.copy(src, dest) {
+                            println!("{}", err);
+                        }
+                    }
+                    None => println!("invalid address '{}'", dest),
+                },
+                _ => println!("usage: copy range dest"),
+            },
+            "snap" => {
+                let name = args.first().unwrap_or(&"0").to_string();
+                self.snapshots.insert(name.clone(), self.cpu.snapshot());
+                println!("snapshot {} taken", name);
+            }
+            "cmp" => self.compare(args),
+            "l" | "list" => self.list(args),
+            "rs" | "rstep" => {
+                let count = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+                let undone = self.cpu.rewind(count);
+                if undone < count {
+                    println!("stepped back {} instructions, no more history", undone);
+                }
+                self.show_position();
+            }
+            "rwrite" => match args.first().and_then(|a| self.address(a)) {
+                Some(addr) => match self.cpu.rewind_to_write(addr) {
+                    Some(undone) => {
+                        println!("stepped back {} instructions", undone);
+                        self.show_position();
+                    }
+                    None => println!("no recorded write to {:04X}", addr),
+                },
+                None => println!("usage: rwrite addr"),
+            },
+            "record" => match args.first() {
+                Some(&"off") => self.cpu.stop_recording(),
+                Some(n) => match n.parse() {
+                    Ok(n) => self.cpu.record_history(n),
+                    Err(_) => println!("usage: record [n|off]"),
+                },
+                None => match self.cpu.history() {
+                    Some(h) => println!("{} of {} instructions recorded", h.len(), h.capacity()),
+                    None => println!("not recording"),
+                },
+            },
+            "h" | "history" => {
+                for (i, line) in self.history.iter().enumerate() {
+                    println!("{:>4}  {}", i, line);
+                }
+            }
+            "q" | "quit" => return false,
+            "help" | "?" => println!("{}", HELP),
+            other => println!("unknown command '{}', try help", other),
+        }
+        true
+    }
+
+    fn backtrace(&self) {
+        if let Some(calls) = self.cpu.call_stack() {
+            print!(
+                "{}",
+                calls.backtrace_with(self.cpu.state().pc, &|a| self.symbols.label(a))
+            );
+        }
+    }
+
+    // Run the CPU for up to `limit` instructions, turning a panic (such as
+    // an unimplemented opcode) into a message so the session survives it.
+    // Returns that didn't match the call stack are reported as they happen.
+    fn execute(&mut self, limit: usize) -> Option<StopReason> {
+        let mismatches = self.mismatch_count();
+        let cpu = &mut self.cpu;
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cpu.run(limit)));
+        panic::set_hook(hook);
+        if let Some(calls) = self.cpu.call_stack() {
+            for mismatch in calls.mismatches().iter().skip(mismatches) {
+                println!("warning: {}", mismatch);
+            }
+        }
+        match result {
+            Ok(reason) => Some(reason),
+            Err(err) => {
+                println!("CPU error: {}", crate::panic_message(err));
+                self.backtrace();
+                None
+            }
+        }
+    }
+
+    fn mismatch_count(&self) -> usize {
+        self.cpu.call_stack().map_or(0, |c| c.mismatches().len())
+    }
+
+    // Run until the call at PC returns to `ret`, with a temporary
+    // breakpoint that replaces any conditional one already there.
+    fn step_over(&mut self, ret: u16) {
+        let saved = self.cpu.breakpoints().get(ret).cloned();
+        self.cpu.breakpoints_mut().add(ret, None);
+        let reason = self.execute(CONTINUE_LIMIT);
+        match saved {
+            Some(bp) => self.cpu.breakpoints_mut().add(bp.addr, bp.condition),
+            None => {
+                self.cpu.breakpoints_mut().remove(ret);
+            }
+        }
+        match reason {
+            Some(StopReason::Breakpoint(addr)) if addr == ret => {}
+            Some(reason) => println!("{}", reason),
+            None => {}
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) {
+        let (args, condition) = match split_condition(args) {
+            Ok(split) => split,
+            Err(err) => return println!("{}", err),
+        };
+        let Some(addr) = args.first() else {
+            return self.list_breakpoints();
+        };
+        match self.address(addr) {
+            Some(addr) => {
+                self.cpu.breakpoints_mut().add(addr, condition);
+                println!("breakpoint at {:04X}", addr);
+            }
+            None => println!("invalid address '{}'", addr),
+        }
+    }
+
+    fn list_breakpoints(&self) {
+        let bps = self.cpu.breakpoints();
+        let suffix = |c: &Option<Condition>| match c {
+            Some(c) => format!("  if {}", c),
+            None => String::new(),
+        };
+        for bp in bps.breakpoints() {
+            println!("{}{}", self.format_line("", bp.addr), suffix(&bp.condition));
+        }
+        for w in bps.watchpoints() {
+            println!(
+                "watch {:?} {:04X}-{:04X}{}",
+                w.kind,
+                w.range.start(),
+                w.range.end(),
+                suffix(&w.condition)
+            );
+        }
+        for p in bps.ports() {
+            println!("port {:?} {:02X}{}", p.kind, p.port, suffix(&p.condition));
+        }
+    }
+
+    fn watch(&mut self, args: &[&str], add: bool) {
+        let (args, condition) = match split_condition(args) {
+            Ok(split) => split,
+            Err(err) => return println!("{}", err),
+        };
+        let (kind, args) = match args.first().and_then(|a| parse_kind(a)) {
+            Some(kind) => (kind, &args[1..]),
+            None => (WatchKind::Write, args),
+        };
+        let Some(range) = args.first().and_then(|a| self.range(a)) else {
+            return println!("usage: watch [r|w|rw] addr[-end] [if condition]");
+        };
+        let bps = self.cpu.breakpoints_mut();
+        if add {
+            bps.add_watch(range, kind, condition);
+        } else if !bps.remove_watch(range, kind) {
+            println!("no such watchpoint");
+        }
+    }
+
+    fn port(&mut self, args: &[&str], add: bool) {
+        let (args, condition) = match split_condition(args) {
+            Ok(split) => split,
+            Err(err) => return println!("{}", err),
+        };
+        let (kind, args) = match args.first().and_then(|a| parse_kind(a)) {
+            Some(kind) => (kind, &args[1..]),
+            None => (WatchKind::ReadWrite, args),
+        };
+        let Some(port) = args
+            .first()
+            .and_then(|a| parse_number(a))
+            .filter(|p| *p <= 0xFF)
+        else {
+            return println!("usage: port [in|out|io] n [if condition]");
+        };
+        let bps = self.cpu.breakpoints_mut();
+        if add {
+            bps.add_port(port as u8, kind, condition);
+        } else if !bps.remove_port(port as u8, kind) {
+            println!("no such port breakpoint");
+        }
+    }
+
+    fn show_position(&self) {
+        let state = self.cpu.state();
+        println!("{}", state);
+        println!("{}", self.format_line("", state.pc));
+    }
+
+    fn set_register(&mut self, args: &[&str]) {
+        let (Some(name), Some(value)) = (args.first(), args.get(1).and_then(|v| self.address(v)))
+        else {
+            println!("usage: set reg value");
+            return;
+        };
+        let mut state = self.cpu.state();
+        let [hi, lo] = value.to_be_bytes();
+        match name.to_ascii_lowercase().as_str() {
+            "a" => state.a = lo,
+            "b" => state.b = lo,
+            "c" => state.c = lo,
+            "d" => state.d = lo,
+            "e" => state.e = lo,
+            "h" => state.h = lo,
+            "l" => state.l = lo,
+            "bc" => (state.b, state.c) = (hi, lo),
+            "de" => (state.d, state.e) = (hi, lo),
+            "hl" => (state.h, state.l) = (hi, lo),
+            "sp" => state.sp = value,
+            "pc" => state.pc = value,
+            "flags" | "f" => state.flags = lo,
+            other => {
+                println!("unknown register '{}'", other);
+                return;
+            }
+        }
+        self.cpu.set_state(&state);
+        println!("{}", state);
+    }
+
+    fn examine(&self, args: &[&str]) {
+        let Some(start) = args.first().and_then(|a| self.address(a)) else {
+            println!("usage: x addr [len]");
+            return;
+        };
+        let len = args.get(1).and_then(|l| parse_number(l)).unwrap_or(0x40);
+        let end = start.saturating_add(len.max(1) - 1);
+        print!(
+            "{}",
+            hexfile::hexdump(start, self.cpu.memory_range(start..=end))
+        );
+    }
+
+    fn write_memory(&mut self, args: &[&str]) {
+        let Some(start) = args.first().and_then(|a| self.address(a)) else {
+            println!("usage: w addr byte...");
+            return;
+        };
+        let mut bytes = Vec::new();
+        for arg in &args[1..] {
+            match parse_number(arg) {
+                Some(value) if value <= 0xFF => bytes.push(value as u8),
+                _ => {
+                    println!("invalid byte '{}'", arg);
+                    return;
+                }
+            }
+        }
+        match self.cpu.load_at(start, &bytes) {
+            Ok(()) => println!("wrote {} bytes at {:04X}", bytes.len(), start),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    // Search all of memory, or the range given before the pattern. A first
+    // argument is only taken as a range when it has a '-' in it.
+    fn find(&self, args: &[&str]) {
+        let (range, args) = match args
+            .first()
+            .filter(|a| a.contains('-') && !a.starts_with('"'))
+        {
+            Some(arg) => match self.range(arg) {
+                Some(range) => (range, &args[1..]),
+                None => return println!("invalid range '{}'", arg),
+            },
+            None => (0..=0xFFFF, args),
+        };
+        let pattern = match Pattern::parse(&args.join(" ")) {
+            Ok(pattern) => pattern,
+            Err(err) => return println!("{}", err),
+        };
+        let found = self.cpu.search(range, &pattern);
+        for addr in found.iter().take(FIND_LIMIT) {
+            match self.symbols.label(*addr) {
+                Some(label) => println!("{:04X}  {}", addr, label),
+                None => println!("{:04X}", addr),
+            }
+        }
+        if found.len() > FIND_LIMIT {
+            println!("... {} more", found.len() - FIND_LIMIT);
+        }
+        println!("{} matches", found.len());
+    }
+
+    fn fill(&mut self, args: &[&str]) {
+        let Some(range) = args.first().and_then(|a| self.range(a)) else {
+            return println!("usage: fill range pattern");
+        };
+        match Pattern::parse(&args[1..].join(" ")) {
+            Ok(pattern) => self.cpu.fill(range, &pattern.bytes()),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    // Compare a snapshot with current memory, or two snapshots.
+    fn compare(&self, args: &[&str]) {
+        let current;
+        let (old, new) = match args {
+            [old] => {
+                current = self.cpu.snapshot();
+                (self.snapshots.get(*old), Some(&current))
+            }
+            [old, new] => (self.snapshots.get(*old), self.snapshots.get(*new)),
+            _ => return println!("usage: cmp name [name2]"),
+        };
+        let (Some(old), Some(new)) = (old, new) else {
+            return println!("no such snapshot");
+        };
+        let changes = old.compare(new, 0..=0xFFFF);
+        for change in &changes {
+            println!(
+                "{:04X}  {:02X} -> {:02X}{}",
+                change.addr,
+                change.old,
+                change.new,
+                self.symbols
+                    .label(change.addr)
+                    .map(|l| format!("  {}", l))
+                    .unwrap_or_default()
+            );
+        }
+        println!("{} bytes changed", changes.len());
+    }
+
+    fn list(&self, args: &[&str]) {
+        let pc = self.cpu.state().pc;
+        let count = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(10);
+        let start = match args.first() {
+            Some(addr) => match self.address(addr) {
+                Some(addr) => addr,
+                None => {
+                    println!("invalid address '{}'", addr);
+                    return;
+                }
+            },
+            None => self.context_start(pc),
+        };
+        let mut addr = start;
+        for _ in 0..count {
+            let instr = self.cpu.disassemble(addr);
+            let marker = if instr.addr == pc { "=>" } else { "  " };
+            let bp = if self.cpu.breakpoints().get(instr.addr).is_some() {
+                '*'
+            } else {
+                ' '
+            };
+            println!("{}", self.format_line(&format!("{}{} ", marker, bp), addr));
+            addr = instr.next_addr();
+        }
+    }
+
+    // Find an address a few instructions before `pc` whose linear
+    // disassembly lands exactly on `pc`, so listings show some context.
+    fn context_start(&self, pc: u16) -> u16 {
+        for back in (1..=9u16).rev() {
+            let start = pc.wrapping_sub(back);
+            let mut addr = start;
+            let mut count = 0;
+            while addr < pc && count < 4 {
+                addr = self.cpu.disassemble(addr).next_addr();
+                count += 1;
+            }
+            if addr == pc && count >= 3 {
+                return start;
+            }
+        }
+        pc
+    }
+}
@@ -1,19 +1,547 @@
+mod debugger;
+
 use intel8080::*;
+use std::any::Any;
+use std::collections::VecDeque;
 use std::env;
 use std::fs::File;
 // use std::io;
 use std::io::Read;
-fn main() {
-    let args: Vec<_> = env::args().collect();
-    let mut cpu: Intel8080 = Intel8080::new();
-    let mut rom: File = File::open(&args[1]).expect("Unable to open file");
-    let mut buffer: Vec<u8> = Vec::new();
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+
+// Each argument is a ROM image, optionally followed by the address it should
+// be loaded at: `invaders.h@0000 invaders.g@0800 ...` or `prog.com@0100`.
+// Intel HEX (.hex, .ihx) and S-record (.s19, .srec, .mot) files carry their
+// own addresses, and assembly source (.asm) is assembled first. Execution
+// starts at the entry point of the first file that has one, or else at the
+// address of the first image.
+//
+// Symbol files (.sym) may be given among the images; their symbols, and
+// those of any assembled source, label addresses in the output.
+fn parse_image(arg: &str) -> Image {
+    let ext = arg.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    if ext == "asm" {
+        return assemble(arg).image;
+    }
+    let parser = match ext.as_str() {
+        "hex" | "ihx" => hexfile::parse_ihex,
+        "s19" | "srec" | "mot" => hexfile::parse_srec,
+        _ => {
+            return Image {
+                segments: vec![parse_segment(arg)],
+                entry: None,
+            }
+        }
+    };
+    let text = std::fs::read_to_string(arg).expect("Unable to open file");
+    parser(&text).unwrap_or_else(|err| {
+        eprintln!("{}: {}", arg, err);
+        std::process::exit(1);
+    })
+}
+
+fn assemble(path: &str) -> asm::Assembly {
+    asm::assemble_file(path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
 
+fn parse_segment(arg: &str) -> Segment {
+    let (path, addr) = match arg.rsplit_once('@') {
+        Some((path, addr)) => (
+            path,
+            u16::from_str_radix(addr, 16).expect("Invalid load address"),
+        ),
+        None => (arg, 0),
+    };
+    let mut rom: File = File::open(path).expect("Unable to open file");
+    let mut buffer: Vec<u8> = Vec::new();
     rom.read_to_end(&mut buffer).unwrap();
-    cpu.load(&buffer);
+    Segment::new(addr, buffer)
+}
+
+fn is_symbol_file(path: &str) -> bool {
+    path.rsplit('.')
+        .next()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("sym"))
+}
+
+// The symbols from every .sym file and assembly source named on the
+// command line.
+fn load_symbols(paths: &[String]) -> symbols::SymbolTable {
+    let mut table = symbols::SymbolTable::new();
+    for path in paths {
+        if is_symbol_file(path) {
+            let text = std::fs::read_to_string(path).expect("Unable to open file");
+            match symbols::SymbolTable::parse(&text) {
+                Ok(symbols) => table.merge(&symbols),
+                Err(err) => {
+                    eprintln!("{}: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        } else if path.to_ascii_lowercase().ends_with(".asm") {
+            table.merge(&symbols::SymbolTable::from(&assemble(path).symbols));
+        }
+    }
+    table
+}
+
+// Load every file named on the command line into a fresh CPU.
+fn load_program(paths: &[String]) -> (Intel8080, Image) {
+    let mut cpu: Intel8080 = Intel8080::new();
+    let images: Vec<Image> = paths
+        .iter()
+        .filter(|arg| !is_symbol_file(arg))
+        .map(|arg| parse_image(arg))
+        .collect();
+    let image = Image {
+        segments: images.iter().flat_map(|i| i.segments.clone()).collect(),
+        entry: images.iter().find_map(|i| i.entry),
+    };
+
+    cpu.set_pc(image.segments.first().map_or(0, |s| s.addr));
+    if let Err(err) = cpu.load_image(&image) {
+        eprintln!("Unable to load: {}", err);
+        std::process::exit(1);
+    }
+    (cpu, image)
+}
+
+// `check` gives up on a program that hasn't halted after this many
+// instructions.
+const CHECK_LIMIT: usize = 10_000_000;
 
-    while cpu.get_pc() < (buffer.len() as u16) {
+// Run until execution leaves the loaded segments or the CPU halts.
+fn execute_program(cpu: &mut Intel8080, image: &Image) {
+    let in_rom = |pc: u16| {
+        image
+            .segments
+            .iter()
+            .any(|s| (pc as usize) >= s.addr as usize && (pc as usize) < s.end())
+    };
+    while in_rom(cpu.get_pc()) && !cpu.is_halted() {
         cpu.tick();
-        cpu.print_state();
+    }
+}
+
+// execute_program, reporting a CPU failure (an unimplemented opcode) as a
+// one-line message instead of a panic. Returns false if the CPU failed.
+fn execute_checked(cpu: &mut Intel8080, image: &Image) -> bool {
+    catch_failure(cpu, |cpu| execute_program(cpu, image))
+}
+
+// Run `f`, reporting a CPU failure the way execute_checked does.
+fn catch_failure(cpu: &mut Intel8080, f: impl FnOnce(&mut Intel8080)) -> bool {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(cpu)));
+    panic::set_hook(hook);
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!(
+                "Execution stopped at {:04X}: {}",
+                cpu.state().pc,
+                panic_message(err)
+            );
+            false
+        }
+    }
+}
+
+// The message a panic was raised with.
+fn panic_message(err: Box<dyn Any + Send>) -> String {
+    err.downcast_ref::<String>()
+        .cloned()
+        .or_else(|| err.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown error".into())
+}
+
+// `profile [--top n] [--folded file] files...`: run the program and print
+// where it spent its time. --folded also writes the folded call stacks for
+// flame graph tools.
+fn profile(args: &[String]) {
+    let mut top = 20;
+    let mut folded_path = None;
+    let mut args = args;
+    while let [flag, value, rest @ ..] = args {
+        match flag.as_str() {
+            "--top" => top = value.parse().expect("Invalid count"),
+            "--folded" => folded_path = Some(value.clone()),
+            _ => break,
+        }
+        args = rest;
+    }
+
+    let (mut cpu, image) = load_program(args);
+    let symbols = load_symbols(args);
+    cpu.attach_profiler(profile::Profiler::new());
+    // Report whatever was collected even if the CPU gives up part way.
+    execute_checked(&mut cpu, &image);
+    let profiler = cpu.detach_profiler().expect("profiler attached");
+    let name = |addr| symbols.label(addr);
+    print!("{}", profiler.report_with(cpu.memory(), top, &name));
+    if let Some(path) = folded_path {
+        std::fs::write(&path, profiler.folded_with(&name)).expect("Unable to write file");
+    }
+}
+
+// `coverage [--listing file] files...`: run the program, then summarise
+// which bytes of each loaded segment were executed, read and written, and
+// which opcodes ran. --listing writes an annotated disassembly as well.
+fn coverage(args: &[String]) {
+    let (listing_path, args) = match args {
+        [flag, path, rest @ ..] if flag == "--listing" => (Some(path), rest),
+        _ => (None, args),
+    };
+    let (mut cpu, image) = load_program(args);
+    cpu.attach_coverage(coverage::Coverage::new());
+    execute_checked(&mut cpu, &image);
+    let coverage = cpu.detach_coverage().expect("coverage attached");
+    let ranges: Vec<_> = image
+        .segments
+        .iter()
+        .filter(|s| !s.data.is_empty())
+        .map(|s| s.addr..=(s.end() - 1) as u16)
+        .collect();
+    print!(
+        "{}\n{}",
+        coverage.summary(&ranges),
+        coverage.opcode_report()
+    );
+    if let Some(path) = listing_path {
+        let listing: String = ranges
+            .iter()
+            .map(|r| coverage.listing(cpu.memory(), r.clone()))
+            .collect();
+        std::fs::write(path, listing).expect("Unable to write file");
+    }
+}
+
+// `check [--rom range]... [--code range]... [--stack range] files...`: run
+// the program under the memory checker until it halts, or for at most
+// CHECK_LIMIT instructions, and list what it found. Unlike the other
+// subcommands it keeps going when execution leaves the loaded images, so
+// that running into unloaded memory is caught. The loaded images count as
+// initialised, and as the code regions unless --code is given. Exits with
+// status 1 if anything was found.
+fn check(args: &[String]) {
+    let mut rom = Vec::new();
+    let mut code = Vec::new();
+    let mut stack = None;
+    let mut args = args;
+    while let [flag, value, rest @ ..] = args {
+        match flag.as_str() {
+            "--rom" => rom.push(parse_range(value)),
+            "--code" => code.push(parse_range(value)),
+            "--stack" => stack = Some(parse_range(value)),
+            _ => break,
+        }
+        args = rest;
+    }
+
+    let (mut cpu, image) = load_program(args);
+    let mut sanitizer = if code.is_empty() {
+        sanitizer::Sanitizer::for_image(&image)
+    } else {
+        let mut sanitizer = sanitizer::Sanitizer::new();
+        for seg in image.segments.iter().filter(|s| !s.data.is_empty()) {
+            sanitizer.mark_initialized(seg.addr..=(seg.end() - 1) as u16);
+        }
+        for range in code {
+            sanitizer.add_code(range);
+        }
+        sanitizer
+    };
+    for range in rom {
+        sanitizer.add_rom(range);
+    }
+    if let Some(range) = stack {
+        sanitizer.set_stack(range);
+    }
+    cpu.attach_sanitizer(sanitizer);
+    catch_failure(&mut cpu, |cpu| {
+        for _ in 0..CHECK_LIMIT {
+            if cpu.is_halted() {
+                return;
+            }
+            cpu.tick();
+        }
+        eprintln!("Stopped after {} instructions without halting", CHECK_LIMIT);
+    });
+    let sanitizer = cpu.detach_sanitizer().expect("sanitizer attached");
+    for violation in sanitizer.violations() {
+        println!("{}", violation);
+    }
+    match sanitizer.violations().len() {
+        0 => println!("No problems found"),
+        n => {
+            println!("{} problems found", n);
+            std::process::exit(1);
+        }
+    }
+}
+
+// `smc files...`: run the program and print each write to a byte that was
+// already executed, as it happens.
+fn smc(paths: &[String]) {
+    let (mut cpu, image) = load_program(paths);
+    let symbols = load_symbols(paths);
+    cpu.attach_smc_detector(smc::SmcDetector::new().with_hook(move |write| {
+        match symbols.label(write.addr) {
+            Some(label) => println!("{} ({})", write, label),
+            None => println!("{}", write),
+        }
+    }));
+    execute_checked(&mut cpu, &image);
+    let detector = cpu.detach_smc_detector().expect("detector attached");
+    println!("{} writes to code", detector.writes().len());
+}
+
+// `run [--trace file] [--format native|registers|pcmem] [--range start-end]
+// [--state compact|table|json] files...`: run until execution leaves the
+// loaded program or halts, tracing each instruction to stdout or the --trace
+// file. Each --range limits the trace to instructions inside it. --state
+// prints the final CPU state to stderr in the given format. If the CPU
+// fails, the call stack at that point is printed.
+fn run(args: &[String]) {
+    let mut trace_path = None;
+    let mut format = trace::TraceFormat::Native;
+    let mut ranges = Vec::new();
+    let mut state_format = None;
+    let mut args = args;
+    while let [flag, value, rest @ ..] = args {
+        match flag.as_str() {
+            "--trace" => trace_path = Some(value.clone()),
+            "--format" => {
+                format = trace::TraceFormat::from_name(value).unwrap_or_else(|| {
+                    eprintln!("Unknown trace format '{}'", value);
+                    std::process::exit(1);
+                })
+            }
+            "--range" => ranges.push(parse_range(value)),
+            "--state" => {
+                state_format = Some(state::StateFormat::from_name(value).unwrap_or_else(|| {
+                    eprintln!("Unknown state format '{}'", value);
+                    std::process::exit(1);
+                }))
+            }
+            _ => break,
+        }
+        args = rest;
+    }
+
+    let (mut cpu, image) = load_program(args);
+    let symbols = load_symbols(args);
+    let mut tracer = match trace_path {
+        Some(path) => trace::Tracer::new(
+            File::create(&path).expect("Unable to create trace file"),
+            format,
+        ),
+        None => trace::Tracer::new(std::io::stdout(), format),
+    };
+    for range in ranges {
+        tracer.add_range(range);
+    }
+    cpu.attach_tracer(tracer.with_symbols(symbols.clone()));
+    cpu.track_calls();
+    let completed = execute_checked(&mut cpu, &image);
+    if let Some(Err(err)) = cpu.detach_tracer().map(trace::Tracer::finish) {
+        eprintln!("Unable to write trace: {}", err);
+    }
+    if let Some(calls) = cpu.call_stack() {
+        for mismatch in calls.mismatches() {
+            eprintln!("warning: {}", mismatch);
+        }
+    }
+    if let Some(format) = state_format {
+        eprintln!("{}", cpu.format_state(format));
+    }
+    if !completed {
+        let calls = cpu.call_stack().expect("call tracking on");
+        eprint!(
+            "Backtrace:\n{}",
+            calls.backtrace_with(cpu.state().pc, &|a| symbols.label(a))
+        );
+        std::process::exit(101);
+    }
+}
+
+// A hex address range written start-end.
+fn parse_range(text: &str) -> std::ops::RangeInclusive<u16> {
+    let parse = |s: &str| u16::from_str_radix(s, 16).expect("Invalid address range");
+    match text.split_once('-') {
+        Some((start, end)) => parse(start)..=parse(end),
+        None => parse(text)..=parse(text),
+    }
+}
+
+// `debug files...`: load the program and drop into the interactive debugger.
+fn debug(paths: &[String]) {
+    let (cpu, _) = load_program(paths);
+    debugger::Debugger::new(cpu, load_symbols(paths)).run();
+}
+
+// `gdb [--port n] files...`: wait for a GDB remote protocol client on
+// localhost (port 1234 by default) and serve it.
+fn gdb(args: &[String]) {
+    let (port, paths) = match args {
+        [flag, port, rest @ ..] if flag == "--port" => {
+            (port.parse::<u16>().expect("Invalid port"), rest)
+        }
+        _ => (1234, args),
+    };
+    let (mut cpu, _) = load_program(paths);
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+        eprintln!("Unable to listen on port {}: {}", port, err);
+        std::process::exit(1);
+    });
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let (stream, peer) = listener.accept().expect("Unable to accept connection");
+    println!("Connected to {}", peer);
+    if let Err(err) = gdb::serve(&mut cpu, stream) {
+        eprintln!("Connection lost: {}", err);
+    }
+}
+
+// `trace-diff reference.log files...`: run the program in step with a trace
+// recorded by another emulator (any format `trace::TraceRecord` reads) and
+// stop at the first line whose registers differ.
+fn trace_diff(args: &[String]) {
+    const CONTEXT: usize = 8;
+    let Some((reference, paths)) = args.split_first() else {
+        eprintln!("usage: trace-diff reference.log files...");
+        std::process::exit(1);
+    };
+    let text = std::fs::read_to_string(reference).expect("Unable to open file");
+    let (mut cpu, _) = load_program(paths);
+    // Trace lines of the last few instructions executed, with their PC.
+    let mut recent: VecDeque<(u16, String)> = VecDeque::with_capacity(CONTEXT);
+    let mut count = 0;
+    for (i, line) in text.lines().enumerate() {
+        let Some(expected) = trace::TraceRecord::parse(line) else {
+            continue;
+        };
+        let actual = trace::TraceRecord::from_state(&cpu.state());
+        let differences = expected.differences(&actual);
+        if !differences.is_empty() {
+            println!(
+                "First divergence at {}:{}, after {} instructions{}",
+                reference,
+                i + 1,
+                count,
+                if cpu.is_halted() { " (CPU halted)" } else { "" }
+            );
+            for (name, want, got) in differences {
+                let width = if name.len() == 2 { 4 } else { 2 };
+                println!(
+                    "  {:<2}  expected {:0w$X}, got {:0w$X}",
+                    name,
+                    want,
+                    got,
+                    w = width
+                );
+            }
+            println!("\nContext:");
+            for (_, line) in &recent {
+                println!("  {}", line);
+            }
+            println!("\nExpected: {}", line.trim());
+            println!(
+                "Actual:   {}",
+                trace::format_line(&cpu, trace::TraceFormat::Native)
+            );
+            match recent.back() {
+                Some((pc, _)) => println!(
+                    "\nResponsible instruction:\n  {}",
+                    disasm::format_line(&cpu.disassemble(*pc))
+                ),
+                None => println!("\nThe initial state differs."),
+            }
+            std::process::exit(1);
+        }
+
+        if recent.len() == CONTEXT {
+            recent.pop_front();
+        }
+        let pc = cpu.get_pc();
+        recent.push_back((pc, trace::format_line(&cpu, trace::TraceFormat::Native)));
+        if !catch_failure(&mut cpu, |cpu| cpu.tick()) {
+            println!(
+                "CPU error after {} instructions, executing:\n  {}",
+                count,
+                disasm::format_line(&cpu.disassemble(pc))
+            );
+            std::process::exit(1);
+        }
+        count += 1;
+    }
+    println!("{} instructions match {}", count, reference);
+}
+
+// `disasm [--flow] files...`: a linear listing of every loaded segment, or
+// with --flow a reassemblable listing that follows jumps and calls from the
+// entry point and the RST vectors and leaves unreached bytes as data.
+fn disasm(args: &[String]) {
+    let flow = args.first().is_some_and(|a| a == "--flow");
+    let paths = if flow { &args[1..] } else { args };
+    let (cpu, image) = load_program(paths);
+    let symbols = load_symbols(paths);
+    for seg in image.segments.iter().filter(|s| !s.data.is_empty()) {
+        let range = seg.addr..=(seg.end() - 1) as u16;
+        if flow {
+            let mut entries = vec![image.entry.unwrap_or(seg.addr), seg.addr];
+            entries.extend_from_slice(&disasm::flow::RST_VECTORS);
+            let mut analysis = disasm::flow::analyze(cpu.memory(), range, &entries);
+            analysis.rename_labels(&symbols);
+            print!("{}", analysis.listing());
+        } else {
+            for instr in disasm::disassemble_range(cpu.memory(), range) {
+                if let Some(name) = symbols.name_at(instr.addr) {
+                    println!("{}:", name);
+                }
+                println!(
+                    "{}",
+                    disasm::format_line_with(&instr, &|a| symbols.label(a))
+                );
+            }
+        }
+    }
+}
+
+// `asm file.asm`: assemble to file.hex, and write the listing to file.lst
+// and the symbol table to file.sym.
+fn assemble_command(paths: &[String]) {
+    for path in paths {
+        let assembly = assemble(path);
+        let base = path.strip_suffix(".asm").unwrap_or(path);
+        let hex = format!("{}.hex", base);
+        let lst = format!("{}.lst", base);
+        let sym = format!("{}.sym", base);
+        std::fs::write(&hex, hexfile::write_ihex(&assembly.image)).expect("Unable to write file");
+        std::fs::write(&lst, assembly.listing()).expect("Unable to write file");
+        let symbols = symbols::SymbolTable::from(&assembly.symbols);
+        std::fs::write(&sym, symbols.to_sym()).expect("Unable to write file");
+        println!("{} -> {}, {}, {}", path, hex, lst, sym);
+    }
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("asm") => assemble_command(&args[2..]),
+        Some("check") => check(&args[2..]),
+        Some("coverage") => coverage(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("disasm") => disasm(&args[2..]),
+        Some("gdb") => gdb(&args[2..]),
+        Some("profile") => profile(&args[2..]),
+        Some("run") => run(&args[2..]),
+        Some("smc") => smc(&args[2..]),
+        Some("trace-diff") => trace_diff(&args[2..]),
+        _ => run(&args[1..]),
     }
 }
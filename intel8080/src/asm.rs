@@ -0,0 +1,1208 @@
+// Two-pass assembler for Intel 8080 source.
+//
+// Pass one assigns addresses to labels, pass two evaluates operands and
+// emits code. Source lines look like
+//
+//   [label[:]] [mnemonic [operand[,operand]]] [; comment]
+//
+// A label needs a colon unless it starts in the first column, and EQU/SET
+// names never take one. Mnemonics, registers and symbols are not case
+// sensitive. Supported directives: ORG, EQU, SET, DB, DW, DS, END,
+// MACRO/ENDM (with LOCAL), IF/ELSE/ENDIF and INCLUDE.
+//
+// Labels starting with a dot are local to the closest preceding ordinary
+// label, so `.LOOP` after `FILL:` is really `FILL.LOOP`. Labels defined
+// by a macro expansion don't start a new scope.
+pub(crate) mod expr;
+mod listing;
+mod macros;
+
+pub use listing::{LineValue, ListingLine};
+
+use crate::disasm::{ALU, ALU_IMM, CALLS, JUMPS, PAIRS, PAIRS_PSW, REGS, RETURNS};
+use crate::{Image, Intel8080, Segment};
+use expr::ExprError;
+use macros::Macro;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+const DIRECTIVES: [&str; 14] = [
+    "ORG", "EQU", "SET", "DB", "DW", "DS", "END", "MACRO", "ENDM", "LOCAL", "IF", "ELSE", "ENDIF",
+    "INCLUDE",
+];
+
+// Instruction budget for `run`, so a test program that never reaches HLT
+// fails instead of hanging.
+const RUN_STEPS: usize = 1_000_000;
+
+// Guards against a macro that expands itself or a file that includes itself.
+const MAX_NESTING: usize = 32;
+
+const IMPLIED: [(&str, u8); 17] = [
+    ("NOP", 0x00),
+    ("RLC", 0x07),
+    ("RRC", 0x0f),
+    ("RAL", 0x17),
+    ("RAR", 0x1f),
+    ("DAA", 0x27),
+    ("CMA", 0x2f),
+    ("STC", 0x37),
+    ("CMC", 0x3f),
+    ("HLT", 0x76),
+    ("RET", 0xc9),
+    ("XTHL", 0xe3),
+    ("PCHL", 0xe9),
+    ("XCHG", 0xeb),
+    ("DI", 0xf3),
+    ("SPHL", 0xf9),
+    ("EI", 0xfb),
+];
+
+/// An assembly error. `file` is set when the line came from an INCLUDE
+/// file (or from `assemble_file`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: {}", file, self.line, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// The output of a successful assembly: the code, ready for
+/// `Intel8080::load_image`, and every symbol that was defined.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembly {
+    pub image: Image,
+    pub symbols: BTreeMap<String, u16>,
+    pub lines: Vec<ListingLine>,
+}
+
+impl Assembly {
+    /// The .LST listing: every source line with its address and code,
+    /// followed by the symbol table.
+    pub fn listing(&self) -> String {
+        listing::format_listing(&self.lines, &self.symbols)
+    }
+
+    /// All the assembled bytes from the lowest to the highest address
+    /// written, with any gaps filled with zeros.
+    pub fn flat(&self) -> (u16, Vec<u8>) {
+        let segments = self.image.segments.iter().filter(|s| !s.data.is_empty());
+        let Some(start) = segments.clone().map(|s| s.addr).min() else {
+            return (0, Vec::new());
+        };
+        let end = segments.clone().map(|s| s.end()).max().unwrap_or(0);
+        let mut bytes = vec![0; end - start as usize];
+        for seg in segments {
+            let offset = (seg.addr - start) as usize;
+            bytes[offset..offset + seg.data.len()].copy_from_slice(&seg.data);
+        }
+        (start, bytes)
+    }
+}
+
+/// Assemble source text. INCLUDE is an error since there is nowhere to
+/// read files from; use `assemble_file` or `assemble_with` for that.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    assemble_with(source, None, |name| {
+        Err(format!("cannot INCLUDE '{}' from a string", name))
+    })
+}
+
+/// Assemble a file, reading INCLUDE files relative to its directory.
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Assembly, AsmError> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let source = std::fs::read_to_string(path).map_err(|err| AsmError {
+        file: Some(name.clone()),
+        line: 0,
+        message: err.to_string(),
+    })?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    assemble_with(&source, Some(&name), |include| {
+        std::fs::read_to_string(dir.join(include)).map_err(|err| format!("{}: {}", include, err))
+    })
+}
+
+/// Assemble source text named `file`, calling `include` to fetch the text of
+/// each INCLUDE file.
+pub fn assemble_with<F>(
+    source: &str,
+    file: Option<&str>,
+    mut include: F,
+) -> Result<Assembly, AsmError>
+where
+    F: FnMut(&str) -> Result<String, String>,
+{
+    let mut asm = Assembler {
+        main: source_lines(source, file, false),
+        include: Some(&mut include),
+        ..Default::default()
+    };
+    for pass in 1..=2 {
+        asm.run_pass(pass).map_err(|message| AsmError {
+            file: asm.file.clone(),
+            line: asm.line,
+            message,
+        })?;
+    }
+    Ok(Assembly {
+        image: Image {
+            segments: asm.segments,
+            entry: asm.entry,
+        },
+        symbols: asm.symbols,
+        lines: asm.listing,
+    })
+}
+
+// Put each `;`-separated statement on its own line. Used by `run_asm!`,
+// where a whole program is usually written on one line.
+fn split_statements(source: &str) -> String {
+    let bytes = source.as_bytes();
+    let mut out = String::from("\t");
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => {
+                out.push_str(&source[start..i]);
+                out.push_str("\n\t");
+                start = i + 1;
+                i += 1;
+            }
+            _ => match expr::read_quoted(bytes, i) {
+                Some((_, end)) => i = end,
+                None => i += 1,
+            },
+        }
+    }
+    out.push_str(&source[start..]);
+    out
+}
+
+/// Assemble `source`, load it into a fresh CPU and run it until it halts.
+/// Statements may be separated with `;` as well as newlines, which means
+/// there are no comments. Meant for tests: panics if the source doesn't
+/// assemble or the program doesn't reach HLT.
+pub fn run(source: &str) -> Intel8080 {
+    run_on(Intel8080::new(), source)
+}
+
+/// Like `run`, on a CPU prepared by the caller, for instance with coverage
+/// attached.
+pub fn run_on(mut cpu: Intel8080, source: &str) -> Intel8080 {
+    let assembly = assemble(&split_statements(source)).unwrap_or_else(|err| panic!("{}", err));
+    cpu.set_pc(assembly.image.segments.first().map_or(0, |s| s.addr));
+    cpu.load_image(&assembly.image)
+        .unwrap_or_else(|err| panic!("{}", err));
+    for _ in 0..RUN_STEPS {
+        if cpu.is_halted() {
+            return cpu;
+        }
+        cpu.tick();
+    }
+    panic!("program did not halt after {} instructions", RUN_STEPS);
+}
+
+#[derive(Clone)]
+struct SourceLine {
+    text: String,
+    file: Option<String>,
+    line: usize,
+    expansion: bool,
+    included: bool,
+}
+
+fn source_lines(source: &str, file: Option<&str>, included: bool) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            file: file.map(str::to_string),
+            line: i + 1,
+            expansion: false,
+            included,
+        })
+        .collect()
+}
+
+// A file or macro expansion being read.
+struct Frame {
+    lines: Vec<SourceLine>,
+    pos: usize,
+}
+
+struct Conditional {
+    // Whether lines in the current branch are assembled.
+    active: bool,
+    // Whether the enclosing block is active at all.
+    parent_active: bool,
+    seen_else: bool,
+}
+
+// A macro whose body is being collected, with the depth of MACRO blocks
+// nested inside it.
+struct Recording {
+    name: String,
+    params: Vec<String>,
+    body: Vec<String>,
+    depth: usize,
+}
+
+type Include<'a> = dyn FnMut(&str) -> Result<String, String> + 'a;
+
+#[derive(Default)]
+struct Assembler<'a> {
+    pass: u8,
+    file: Option<String>,
+    line: usize,
+    // Kept as usize so running off the end of memory can be reported.
+    pc: usize,
+    symbols: BTreeMap<String, u16>,
+    // Names defined with SET, which may be redefined.
+    variables: Vec<String>,
+    // The last ordinary label, which scopes `.local` labels.
+    scope: String,
+    // Whether the current line comes from a macro expansion.
+    expansion: bool,
+    segments: Vec<Segment>,
+    entry: Option<u16>,
+    ended: bool,
+
+    main: Vec<SourceLine>,
+    include: Option<&'a mut Include<'a>>,
+    included: BTreeMap<String, String>,
+    frames: Vec<Frame>,
+    conditionals: Vec<Conditional>,
+    macros: BTreeMap<String, Macro>,
+    recording: Option<Recording>,
+    serial: usize,
+
+    listing: Vec<ListingLine>,
+    line_bytes: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SymbolKind {
+    Label,
+    Equ,
+    Set,
+}
+
+struct Statement<'a> {
+    label: Option<String>,
+    op: Option<String>,
+    operands: Vec<&'a str>,
+}
+
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    DIRECTIVES.contains(&word.as_str()) || opcode_known(&word)
+}
+
+fn opcode_known(op: &str) -> bool {
+    IMPLIED.iter().any(|(name, _)| *name == op)
+        || [
+            ALU.as_slice(),
+            &ALU_IMM,
+            &JUMPS,
+            &CALLS,
+            &RETURNS,
+            &[
+                "MOV", "MVI", "INR", "DCR", "LXI", "INX", "DCX", "DAD", "PUSH", "POP", "STAX",
+                "LDAX", "LDA", "STA", "LHLD", "SHLD", "JMP", "CALL", "RST", "IN", "OUT",
+            ],
+        ]
+        .iter()
+        .any(|names| names.contains(&op))
+}
+
+// Strip a trailing comment, ignoring semicolons inside quotes.
+pub(crate) fn strip_comment(text: &str) -> &str {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => return &text[..i],
+            b'\'' | b'"' => match expr::read_quoted(bytes, i) {
+                Some((_, end)) => i = end,
+                None => return text,
+            },
+            _ => i += 1,
+        }
+    }
+    text
+}
+
+// Split operands on commas that aren't inside quotes or parentheses.
+pub(crate) fn split_operands(text: &str) -> Vec<&str> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let bytes = text.as_bytes();
+    let mut out = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' => {
+                i = expr::read_quoted(bytes, i).map_or(bytes.len(), |(_, end)| end);
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b',' if depth == 0 => {
+                out.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    out.push(text[start..].trim());
+    out
+}
+
+pub(crate) fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], &text[end..])
+}
+
+fn parse_statement<'a>(text: &'a str, macros: &BTreeMap<String, Macro>) -> Statement<'a> {
+    let text = strip_comment(text);
+    let first_column = !text.starts_with(char::is_whitespace);
+    let (first, rest) = split_word(text);
+    let (second, after_second) = split_word(rest);
+
+    let is_op = |word: &str| is_keyword(word) || macros.contains_key(&word.to_ascii_uppercase());
+    // Names defined by the statement itself never take a colon.
+    let is_naming = ["EQU", "SET", "MACRO"].contains(&second.to_ascii_uppercase().as_str());
+
+    let (label, op, operands) = if let Some(name) = first.strip_suffix(':') {
+        (Some(name), second, after_second)
+    } else if is_naming || (first_column && !first.is_empty() && !is_op(first)) {
+        (Some(first), second, after_second)
+    } else {
+        (None, first, rest)
+    };
+    Statement {
+        label: label.map(|l| l.to_ascii_uppercase()),
+        op: (!op.is_empty()).then(|| op.to_ascii_uppercase()),
+        operands: split_operands(operands),
+    }
+}
+
+fn expect_operands(operands: &[&str], count: usize) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!(
+            "expected {} operand{}, found {}",
+            count,
+            if count == 1 { "" } else { "s" },
+            operands.len()
+        ));
+    }
+    Ok(())
+}
+
+impl Assembler<'_> {
+    fn start_pass(&mut self, pass: u8) {
+        self.pass = pass;
+        self.pc = 0;
+        self.ended = false;
+        self.segments.clear();
+        self.entry = None;
+        self.scope.clear();
+        self.frames = vec![Frame {
+            lines: self.main.clone(),
+            pos: 0,
+        }];
+        self.conditionals.clear();
+        self.macros.clear();
+        self.recording = None;
+        self.serial = 0;
+        self.listing.clear();
+    }
+
+    fn next_line(&mut self) -> Option<SourceLine> {
+        while let Some(frame) = self.frames.last_mut() {
+            if let Some(line) = frame.lines.get(frame.pos) {
+                frame.pos += 1;
+                return Some(line.clone());
+            }
+            self.frames.pop();
+        }
+        None
+    }
+
+    fn run_pass(&mut self, pass: u8) -> Result<(), String> {
+        self.start_pass(pass);
+        while let Some(line) = self.next_line() {
+            self.file = line.file.clone();
+            self.line = line.line;
+            self.expansion = line.expansion;
+            self.line_bytes.clear();
+            let start = self.pc;
+            let value = self.process_line(&line.text)?;
+            if self.pass == 2 {
+                self.listing.push(ListingLine {
+                    line: line.line,
+                    value: value.or_else(|| {
+                        (!self.line_bytes.is_empty()).then_some(LineValue::Addr(start as u16))
+                    }),
+                    bytes: std::mem::take(&mut self.line_bytes),
+                    text: line.text,
+                    expansion: line.expansion,
+                    included: line.included,
+                });
+            }
+            if self.ended {
+                break;
+            }
+        }
+        if let Some(rec) = &self.recording {
+            return Err(format!("MACRO {} has no ENDM", rec.name));
+        }
+        if !self.conditionals.is_empty() {
+            return Err("IF without ENDIF".into());
+        }
+        Ok(())
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    // Handle macro recording, conditionals, INCLUDE and macro calls, and
+    // pass anything else on to `assemble_statement`. Returns the value to
+    // show for the line in the listing, if it isn't simply its address.
+    fn process_line(&mut self, text: &str) -> Result<Option<LineValue>, String> {
+        let stmt = parse_statement(text, &self.macros);
+        let op = stmt.op.as_deref().unwrap_or("");
+
+        if let Some(rec) = &mut self.recording {
+            match op {
+                "MACRO" => rec.depth += 1,
+                "ENDM" if rec.depth > 0 => rec.depth -= 1,
+                "ENDM" => {
+                    let rec = self.recording.take().unwrap_or_else(|| unreachable!());
+                    let params = rec.params;
+                    let body = rec.body;
+                    self.macros.insert(rec.name, Macro { params, body });
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            rec.body.push(text.to_string());
+            return Ok(None);
+        }
+
+        match op {
+            "IF" => {
+                let parent_active = self.active();
+                let active = parent_active && {
+                    expect_operands(&stmt.operands, 1)?;
+                    self.eval(stmt.operands[0])? != 0
+                };
+                self.conditionals.push(Conditional {
+                    active,
+                    parent_active,
+                    seen_else: false,
+                });
+                return Ok(None);
+            }
+            "ELSE" => {
+                let cond = self.conditionals.last_mut().ok_or("ELSE without IF")?;
+                if cond.seen_else {
+                    return Err("more than one ELSE for the same IF".into());
+                }
+                cond.seen_else = true;
+                cond.active = cond.parent_active && !cond.active;
+                return Ok(None);
+            }
+            "ENDIF" => {
+                self.conditionals.pop().ok_or("ENDIF without IF")?;
+                return Ok(None);
+            }
+            _ if !self.active() => return Ok(None),
+            _ => {}
+        }
+
+        match op {
+            "MACRO" => {
+                let name = stmt.label.ok_or("MACRO needs a name")?;
+                self.recording = Some(Recording {
+                    name,
+                    params: stmt
+                        .operands
+                        .iter()
+                        .map(|p| p.to_ascii_uppercase())
+                        .collect(),
+                    body: Vec::new(),
+                    depth: 0,
+                });
+                Ok(None)
+            }
+            "ENDM" => Err("ENDM without MACRO".into()),
+            "LOCAL" => Err("LOCAL outside a macro".into()),
+            "INCLUDE" => {
+                expect_operands(&stmt.operands, 1)?;
+                let name = stmt.operands[0];
+                let name = expr::read_quoted(name.as_bytes(), 0)
+                    .map_or(name.to_string(), |(chars, _)| {
+                        String::from_utf8_lossy(&chars).into_owned()
+                    });
+                self.push_include(&name)?;
+                Ok(None)
+            }
+            _ if self.macros.contains_key(op) => {
+                if let Some(label) = &stmt.label {
+                    self.define(label, self.pc as u16, SymbolKind::Label)?;
+                }
+                let body = self.macros[op].expand(&stmt.operands, &mut self.serial)?;
+                self.push_frame(
+                    body.into_iter()
+                        .map(|text| SourceLine {
+                            text,
+                            file: self.file.clone(),
+                            line: self.line,
+                            expansion: true,
+                            included: false,
+                        })
+                        .collect(),
+                )?;
+                Ok(None)
+            }
+            _ => self.assemble_statement(&stmt),
+        }
+    }
+
+    fn push_frame(&mut self, lines: Vec<SourceLine>) -> Result<(), String> {
+        if self.frames.len() > MAX_NESTING {
+            return Err("macros or INCLUDEs nested too deeply".into());
+        }
+        self.frames.push(Frame { lines, pos: 0 });
+        Ok(())
+    }
+
+    fn push_include(&mut self, name: &str) -> Result<(), String> {
+        if !self.included.contains_key(name) {
+            let include = self.include.as_mut().ok_or("INCLUDE is not available")?;
+            let text = include(name)?;
+            self.included.insert(name.to_string(), text);
+        }
+        let lines = source_lines(&self.included[name], Some(name), true);
+        self.push_frame(lines)
+    }
+
+    // Local labels live under the last ordinary label.
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn eval(&self, text: &str) -> Result<u16, String> {
+        if text.is_empty() {
+            return Err("missing operand".into());
+        }
+        let lookup = |name: &str| self.symbols.get(&self.qualify(name)).copied();
+        match expr::eval(text, self.pc as u16, &lookup) {
+            Ok(value) => Ok(value),
+            // Forward references are resolved in pass two.
+            Err(ExprError::Undefined(_)) if self.pass == 1 => Ok(0),
+            Err(ExprError::Undefined(name)) => Err(format!("undefined symbol '{}'", name)),
+            Err(ExprError::Syntax(message)) => Err(message),
+        }
+    }
+
+    fn eval_byte(&self, text: &str) -> Result<u8, String> {
+        let value = self.eval(text)?;
+        // Negative numbers are fine: -1 is 0FFh.
+        if value > 0xFF && value < 0xFF80 {
+            return Err(format!("value {:#X} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn define(&mut self, name: &str, value: u16, kind: SymbolKind) -> Result<(), String> {
+        let name = self.qualify(name);
+        let name = name.as_str();
+        // Labels a macro defines, LOCAL ones included, leave the scope of
+        // the code around the call alone.
+        if kind == SymbolKind::Label
+            && !name.contains('.')
+            && !name.starts_with("??")
+            && !self.expansion
+        {
+            self.scope = name.to_string();
+        }
+        if !name.bytes().next().is_some_and(expr::is_ident_start)
+            || !name.bytes().all(expr::is_ident_char)
+        {
+            return Err(format!("invalid symbol name '{}'", name));
+        }
+        if name.starts_with('.') {
+            return Err(format!("local label '{}' has no label before it", name));
+        }
+        if is_keyword(name) || REGS.contains(&name) || name == "SP" || name == "PSW" {
+            return Err(format!("'{}' is a reserved word", name));
+        }
+        let is_variable = self.variables.iter().any(|v| v == name);
+        match self.symbols.get(name) {
+            Some(_) if kind == SymbolKind::Set && is_variable => {}
+            Some(_) if kind == SymbolKind::Set || is_variable => {
+                return Err(format!("'{}' is defined both with SET and without", name))
+            }
+            Some(old) if self.pass == 1 => {
+                return Err(format!(
+                    "duplicate symbol '{}' (first value {:#06X})",
+                    name, old
+                ))
+            }
+            // An EQU may legitimately change value in pass two if it refers
+            // to a forward label, but a label that moves means the two passes
+            // disagree about the size of something.
+            Some(old) if kind == SymbolKind::Label && *old != value => {
+                return Err(format!(
+                    "'{}' moved from {:#06X} to {:#06X} between passes",
+                    name, old, value
+                ))
+            }
+            _ => {}
+        }
+        if kind == SymbolKind::Set && !is_variable {
+            self.variables.push(name.to_string());
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.pc + bytes.len() > 0x1_0000 {
+            return Err("code extends past 0FFFFh".into());
+        }
+        if self.pass == 2 {
+            self.line_bytes.extend_from_slice(bytes);
+            match self.segments.last_mut() {
+                Some(seg) if seg.end() == self.pc => seg.data.extend_from_slice(bytes),
+                _ => self
+                    .segments
+                    .push(Segment::new(self.pc as u16, bytes.to_vec())),
+            }
+        }
+        self.pc += bytes.len();
+        Ok(())
+    }
+
+    fn assemble_statement(&mut self, stmt: &Statement) -> Result<Option<LineValue>, String> {
+        let op = stmt.op.as_deref().unwrap_or("");
+        let operands = &stmt.operands;
+
+        if let Some(label) = &stmt.label {
+            if op != "EQU" && op != "SET" {
+                self.define(label, self.pc as u16, SymbolKind::Label)?;
+            }
+        }
+
+        match op {
+            "" => Ok(None),
+            "EQU" | "SET" => {
+                let name = stmt
+                    .label
+                    .as_deref()
+                    .ok_or_else(|| format!("{} needs a name", op))?;
+                expect_operands(operands, 1)?;
+                let value = self.eval(operands[0])?;
+                let kind = if op == "SET" {
+                    SymbolKind::Set
+                } else {
+                    SymbolKind::Equ
+                };
+                self.define(name, value, kind)?;
+                Ok(Some(LineValue::Equ(value)))
+            }
+            "ORG" => {
+                expect_operands(operands, 1)?;
+                self.pc = self.eval(operands[0])? as usize;
+                Ok(Some(LineValue::Addr(self.pc as u16)))
+            }
+            "DS" => {
+                expect_operands(operands, 1)?;
+                let size = self.eval(operands[0])? as usize;
+                if self.pc + size > 0x1_0000 {
+                    return Err("DS extends past 0FFFFh".into());
+                }
+                let start = self.pc as u16;
+                self.pc += size;
+                Ok(Some(LineValue::Addr(start)))
+            }
+            "DB" => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    match expr::read_quoted(operand.as_bytes(), 0) {
+                        // Strings longer than a character constant.
+                        Some((chars, end)) if end == operand.len() && chars.len() != 1 => {
+                            bytes.extend(chars)
+                        }
+                        _ => bytes.push(self.eval_byte(operand)?),
+                    }
+                }
+                if bytes.is_empty() {
+                    return Err("DB needs at least one operand".into());
+                }
+                self.emit(&bytes)?;
+                Ok(None)
+            }
+            "DW" => {
+                if operands.is_empty() {
+                    return Err("DW needs at least one operand".into());
+                }
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    bytes.extend_from_slice(&self.eval(operand)?.to_le_bytes());
+                }
+                self.emit(&bytes)?;
+                Ok(None)
+            }
+            "END" => {
+                if let Some(operand) = operands.first() {
+                    self.entry = Some(self.eval(operand)?);
+                }
+                self.ended = true;
+                Ok(None)
+            }
+            _ => {
+                let bytes = self.encode(op, operands)?;
+                self.emit(&bytes)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn register(&self, text: &str) -> Result<u8, String> {
+        let upper = text.to_ascii_uppercase();
+        if let Some(i) = REGS.iter().position(|r| *r == upper) {
+            return Ok(i as u8);
+        }
+        match self.eval(text)? {
+            value @ 0..=7 => Ok(value as u8),
+            _ => Err(format!("invalid register '{}'", text)),
+        }
+    }
+
+    fn pair(&self, text: &str, names: &[&str]) -> Result<u8, String> {
+        let upper = text.to_ascii_uppercase();
+        names
+            .iter()
+            .position(|r| *r == upper)
+            .map(|i| i as u8)
+            .ok_or_else(|| format!("invalid register pair '{}'", text))
+    }
+
+    fn encode(&self, op: &str, operands: &[&str]) -> Result<Vec<u8>, String> {
+        let find = |table: &[&str]| table.iter().position(|name| *name == op).map(|i| i as u8);
+        let word = |opcode: u8, text: &str| -> Result<Vec<u8>, String> {
+            let [lo, hi] = self.eval(text)?.to_le_bytes();
+            Ok(vec![opcode, lo, hi])
+        };
+
+        if let Some((_, opcode)) = IMPLIED.iter().find(|(name, _)| *name == op) {
+            expect_operands(operands, 0)?;
+            return Ok(vec![*opcode]);
+        }
+        if let Some(cc) = find(&RETURNS) {
+            expect_operands(operands, 0)?;
+            return Ok(vec![0xc0 | cc << 3]);
+        }
+        if let Some(cc) = find(&JUMPS) {
+            expect_operands(operands, 1)?;
+            return word(0xc2 | cc << 3, operands[0]);
+        }
+        if let Some(cc) = find(&CALLS) {
+            expect_operands(operands, 1)?;
+            return word(0xc4 | cc << 3, operands[0]);
+        }
+        if let Some(alu) = find(&ALU) {
+            expect_operands(operands, 1)?;
+            return Ok(vec![0x80 | alu << 3 | self.register(operands[0])?]);
+        }
+        if let Some(alu) = find(&ALU_IMM) {
+            expect_operands(operands, 1)?;
+            return Ok(vec![0xc6 | alu << 3, self.eval_byte(operands[0])?]);
+        }
+
+        match op {
+            "MOV" => {
+                expect_operands(operands, 2)?;
+                let dst = self.register(operands[0])?;
+                let src = self.register(operands[1])?;
+                if dst == 6 && src == 6 {
+                    return Err("MOV M,M is not a valid instruction".into());
+                }
+                Ok(vec![0x40 | dst << 3 | src])
+            }
+            "MVI" => {
+                expect_operands(operands, 2)?;
+                let reg = self.register(operands[0])?;
+                Ok(vec![0x06 | reg << 3, self.eval_byte(operands[1])?])
+            }
+            "INR" | "DCR" => {
+                expect_operands(operands, 1)?;
+                let base = if op == "INR" { 0x04 } else { 0x05 };
+                Ok(vec![base | self.register(operands[0])? << 3])
+            }
+            "LXI" => {
+                expect_operands(operands, 2)?;
+                word(0x01 | self.pair(operands[0], &PAIRS)? << 4, operands[1])
+            }
+            "INX" | "DCX" | "DAD" => {
+                expect_operands(operands, 1)?;
+                let base = match op {
+                    "INX" => 0x03,
+                    "DCX" => 0x0b,
+                    _ => 0x09,
+                };
+                Ok(vec![base | self.pair(operands[0], &PAIRS)? << 4])
+            }
+            "PUSH" | "POP" => {
+                expect_operands(operands, 1)?;
+                let base = if op == "PUSH" { 0xc5 } else { 0xc1 };
+                Ok(vec![base | self.pair(operands[0], &PAIRS_PSW)? << 4])
+            }
+            "STAX" | "LDAX" => {
+                expect_operands(operands, 1)?;
+                let base = if op == "STAX" { 0x02 } else { 0x0a };
+                Ok(vec![base | self.pair(operands[0], &PAIRS[..2])? << 4])
+            }
+            "LDA" | "STA" | "LHLD" | "SHLD" | "JMP" | "CALL" => {
+                expect_operands(operands, 1)?;
+                let opcode = match op {
+                    "LDA" => 0x3a,
+                    "STA" => 0x32,
+                    "LHLD" => 0x2a,
+                    "SHLD" => 0x22,
+                    "JMP" => 0xc3,
+                    _ => 0xcd,
+                };
+                word(opcode, operands[0])
+            }
+            "RST" => {
+                expect_operands(operands, 1)?;
+                match self.eval(operands[0])? {
+                    n @ 0..=7 => Ok(vec![0xc7 | (n as u8) << 3]),
+                    n => Err(format!("RST {} is out of range 0-7", n)),
+                }
+            }
+            "IN" | "OUT" => {
+                expect_operands(operands, 1)?;
+                let opcode = if op == "IN" { 0xdb } else { 0xd3 };
+                Ok(vec![opcode, self.eval_byte(operands[0])?])
+            }
+            _ => Err(format!("unknown instruction '{}'", op)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes_at(source: &str, addr: u16) -> Vec<u8> {
+        let assembly = assemble(source).unwrap();
+        let seg = assembly
+            .image
+            .segments
+            .iter()
+            .find(|s| s.addr == addr)
+            .expect("no segment at that address");
+        seg.data.clone()
+    }
+
+    fn error(source: &str) -> AsmError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let state = run_asm!("MVI A,5; ADI 3; HLT");
+        assert_eq!(state.a, 8);
+        assert!(state.halted);
+    }
+
+    #[test]
+    fn register_pairs() {
+        let state = run_asm!("LXI H,1234h; LXI D,0001h; DAD D; HLT");
+        assert_eq!((state.h, state.l), (0x12, 0x35));
+    }
+
+    #[test]
+    fn loop_with_label() {
+        let state = run_asm!("MVI B,3; MVI A,0; LOOP: ADI 2; DCR B; JNZ LOOP; HLT");
+        assert_eq!(state.a, 6);
+        assert_eq!(state.b, 0);
+        assert_eq!(state.flags & 0x40, 0x40);
+    }
+
+    #[test]
+    fn org_places_code() {
+        let assembly = assemble("\tORG 100h\n\tNOP\n\tORG 200h\n\tHLT\n").unwrap();
+        let segments = &assembly.image.segments;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            (segments[0].addr, segments[0].data.clone()),
+            (0x100, vec![0x00])
+        );
+        assert_eq!(
+            (segments[1].addr, segments[1].data.clone()),
+            (0x200, vec![0x76])
+        );
+    }
+
+    #[test]
+    fn equ_and_set() {
+        let source = "
+COUNT   EQU 3
+N       SET 1
+        MVI A,COUNT
+        MVI B,N
+N       SET N+1
+        MVI C,N
+";
+        assert_eq!(bytes_at(source, 0), [0x3e, 3, 0x06, 1, 0x0e, 2]);
+        assert!(error("X EQU 1\nX EQU 2\n")
+            .message
+            .contains("duplicate symbol 'X'"));
+        assert!(error("X EQU 1\nX SET 2\n")
+            .message
+            .contains("both with SET"));
+    }
+
+    #[test]
+    fn data_directives() {
+        let source = "\tORG 10h\n\tDB 1,'AB',-1\n\tDW 1234h,$\n\tDS 3\nLAST:\tDB 0\n";
+        let assembly = assemble(source).unwrap();
+        let segments = &assembly.image.segments;
+        assert_eq!(segments[0].addr, 0x10);
+        assert_eq!(
+            segments[0].data,
+            [1, b'A', b'B', 0xFF, 0x34, 0x12, 0x14, 0x00]
+        );
+        // $ is the address of the DW itself. DS reserves space without
+        // emitting anything.
+        assert_eq!(segments[1].addr, 0x1B);
+        assert_eq!(assembly.symbols["LAST"], 0x1B);
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = "\tJMP LATER\n\tLXI H,VALUE\nLATER:\tHLT\nVALUE\tEQU LATER+1\n";
+        assert_eq!(bytes_at(source, 0), [0xc3, 6, 0, 0x21, 7, 0, 0x76]);
+    }
+
+    #[test]
+    fn byte_operands_must_fit() {
+        assert_eq!(
+            bytes_at("\tMVI A,-128\n\tMVI A,0FFh\n", 0),
+            [0x3e, 0x80, 0x3e, 0xff]
+        );
+        assert!(error("\tMVI A,100h\n")
+            .message
+            .contains("does not fit in a byte"));
+        assert!(error("\tDB 300\n")
+            .message
+            .contains("does not fit in a byte"));
+        assert!(error("\tADI -200\n")
+            .message
+            .contains("does not fit in a byte"));
+    }
+
+    #[test]
+    fn reserved_words_are_not_symbols() {
+        for source in [
+            "MOV: NOP\n",
+            "A EQU 1\n",
+            "SP EQU 1\n",
+            "PSW: NOP\n",
+            "DB EQU 2\n",
+        ] {
+            assert!(
+                error(source).message.contains("is a reserved word"),
+                "{:?} was accepted",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn errors_carry_the_line_number() {
+        let err = error("\tNOP\n\n\tJMP NOWHERE\n\tNOP\n");
+        assert_eq!(err.line, 3);
+        assert_eq!(err.file, None);
+        assert_eq!(err.to_string(), "line 3: undefined symbol 'NOWHERE'");
+
+        let err = assemble_with("\tINCLUDE 'a.inc'\n", Some("main.asm"), |_| {
+            Ok("\tNOP\n\tBOGUS\n".into())
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "a.inc:2: unknown instruction 'BOGUS'");
+    }
+
+    #[test]
+    fn local_labels_belong_to_the_last_label() {
+        let source = "
+FIRST:  MVI B,2
+.LOOP:  DCR B
+        JNZ .LOOP
+SECOND: MVI C,2
+.LOOP:  DCR C
+        JNZ .LOOP
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.symbols["FIRST.LOOP"], 2);
+        assert_eq!(assembly.symbols["SECOND.LOOP"], 8);
+        assert_eq!(&bytes_at(source, 0)[9..], [0xc2, 8, 0]);
+        assert!(error(".LOOP: NOP\n")
+            .message
+            .contains("has no label before it"));
+    }
+
+    #[test]
+    fn macro_labels_keep_the_caller_scope() {
+        let source = "
+WAIT    MACRO
+        LOCAL AGAIN
+AGAIN:  DCR B
+        JNZ AGAIN
+        ENDM
+START:  MVI B,1
+        WAIT
+.DONE:  JMP .DONE
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.symbols["??0001"], 2);
+        assert_eq!(assembly.symbols["START.DONE"], 6);
+        assert!(!assembly
+            .symbols
+            .keys()
+            .any(|name| name.starts_with("??0001.")));
+    }
+
+    #[test]
+    fn macros_expand_with_arguments() {
+        let source = "
+LOAD    MACRO REG,VAL
+        MVI REG,VAL
+        ENDM
+DELAY   MACRO N
+        LOCAL LOOP
+        MVI C,N
+LOOP:   DCR C
+        JNZ LOOP
+        ENDM
+        LOAD A,5
+        LOAD B,'x'
+        DELAY 2
+        DELAY 3
+        HLT
+";
+        assert_eq!(
+            bytes_at(source, 0),
+            [0x3e, 5, 0x06, b'x', 0x0e, 2, 0x0d, 0xc2, 6, 0, 0x0e, 3, 0x0d, 0xc2, 12, 0, 0x76]
+        );
+        assert!(error("M MACRO X\nENDM\n\tM 1,2\n")
+            .message
+            .contains("macro takes 1 arguments, 2 given"));
+        assert!(error("M MACRO\n\tNOP\n")
+            .message
+            .contains("MACRO M has no ENDM"));
+        assert!(error("\tENDM\n").message.contains("ENDM without MACRO"));
+        assert!(error("M MACRO\n\tM\n\tENDM\n\tM\n")
+            .message
+            .contains("nested too deeply"));
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let source = "
+DEBUG   EQU 1
+TRACE   EQU 0
+        IF DEBUG
+        IF TRACE
+        MVI A,1
+        ELSE
+        MVI A,2
+        ENDIF
+        ELSE
+        IF 1
+        MVI A,3
+        ENDIF
+        ENDIF
+        HLT
+";
+        assert_eq!(bytes_at(source, 0), [0x3e, 2, 0x76]);
+        // Operands of inactive IFs are never evaluated.
+        assert_eq!(
+            bytes_at("\tIF 0\n\tIF NOWHERE\n\tENDIF\n\tENDIF\n\tNOP\n", 0),
+            [0]
+        );
+        assert!(error("\tIF 1\n\tNOP\n")
+            .message
+            .contains("IF without ENDIF"));
+        assert!(error("\tELSE\n").message.contains("ELSE without IF"));
+        assert!(error("\tENDIF\n").message.contains("ENDIF without IF"));
+        assert!(error("\tIF 1\n\tELSE\n\tELSE\n\tENDIF\n")
+            .message
+            .contains("more than one ELSE"));
+    }
+
+    #[test]
+    fn include_reads_through_the_callback() {
+        let mut requests = Vec::new();
+        let assembly = assemble_with(
+            "\tINCLUDE 'defs.inc'\n\tMVI A,VALUE\n\tINCLUDE \"defs.inc\"\n",
+            None,
+            |name| {
+                requests.push(name.to_string());
+                Ok("\tNOP\nVALUE\tSET 7\n".into())
+            },
+        )
+        .unwrap();
+        assert_eq!(assembly.image.segments[0].data, [0, 0x3e, 7, 0]);
+        // Each file is only read once.
+        assert_eq!(requests, ["defs.inc"]);
+
+        assert_eq!(
+            error("\tINCLUDE 'x.inc'\n").message,
+            "cannot INCLUDE 'x.inc' from a string"
+        );
+        let err = assemble_with("\tINCLUDE 'gone.inc'\n", None, |name| {
+            Err(format!("{}: not found", name))
+        })
+        .unwrap_err();
+        assert_eq!(err.message, "gone.inc: not found");
+        let err = assemble_with("\tINCLUDE 'self.inc'\n", None, |_| {
+            Ok("\tINCLUDE 'self.inc'\n".into())
+        })
+        .unwrap_err();
+        assert_eq!(err.file.as_deref(), Some("self.inc"));
+        assert!(err.message.contains("nested too deeply"));
+    }
+}
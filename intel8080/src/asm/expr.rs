@@ -0,0 +1,363 @@
+// Expression evaluation for the assembler.
+//
+// Precedence, loosest first, follows Intel's 8080 assembler:
+//   OR XOR
+//   AND
+//   NOT
+//   EQ NE LT LE GT GE
+//   + -
+//   * / MOD SHL SHR
+//   unary - + HIGH LOW
+// All arithmetic is 16-bit and wraps. Relational operators yield 0FFFFh
+// for true and 0 for false so they combine with AND/OR.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprError {
+    Undefined(String),
+    Syntax(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(u16),
+    Ident(String),
+    Op(char),
+    Here,
+}
+
+pub fn parse_number(text: &str) -> Option<u16> {
+    let upper = text.to_ascii_uppercase();
+    let (digits, radix) = match upper.as_bytes().last()? {
+        b'H' => (&upper[..upper.len() - 1], 16),
+        b'B' => (&upper[..upper.len() - 1], 2),
+        b'O' | b'Q' => (&upper[..upper.len() - 1], 8),
+        b'D' => (&upper[..upper.len() - 1], 10),
+        _ => (upper.as_str(), 10),
+    };
+    let value = u32::from_str_radix(digits, radix).ok()?;
+    if value > 0xFFFF {
+        return None;
+    }
+    Some(value as u16)
+}
+
+/// The value of a quoted character constant: one or two characters, the
+/// first one in the high byte.
+pub fn char_constant(chars: &[u8]) -> Option<u16> {
+    match chars {
+        [c] => Some(*c as u16),
+        [hi, lo] => Some(((*hi as u16) << 8) | *lo as u16),
+        _ => None,
+    }
+}
+
+// Split a quoted string starting at `start` (which holds the opening quote),
+// handling '' as an escaped quote. Returns the contents and the index just
+// past the closing quote.
+pub fn read_quoted(text: &[u8], start: usize) -> Option<(Vec<u8>, usize)> {
+    let quote = *text.get(start)?;
+    if quote != b'\'' && quote != b'"' {
+        return None;
+    }
+    let mut out = Vec::new();
+    let mut i = start + 1;
+    while i < text.len() {
+        if text[i] == quote {
+            if text.get(i + 1) == Some(&quote) {
+                out.push(quote);
+                i += 2;
+                continue;
+            }
+            return Some((out, i + 1));
+        }
+        out.push(text[i]);
+        i += 1;
+    }
+    None
+}
+
+pub fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'?' || c == b'@' || c == b'.'
+}
+
+pub fn is_ident_char(c: u8) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ExprError> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let word = &text[start..i];
+            let value = parse_number(word)
+                .ok_or_else(|| ExprError::Syntax(format!("invalid number '{}'", word)))?;
+            tokens.push(Token::Num(value));
+        } else if is_ident_start(c) {
+            let start = i;
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(text[start..i].to_ascii_uppercase()));
+        } else if c == b'\'' || c == b'"' {
+            let (chars, end) = read_quoted(bytes, i)
+                .ok_or_else(|| ExprError::Syntax("unterminated character constant".into()))?;
+            let value = char_constant(&chars).ok_or_else(|| {
+                ExprError::Syntax("character constant must be 1 or 2 characters".into())
+            })?;
+            tokens.push(Token::Num(value));
+            i = end;
+        } else if c == b'$' {
+            tokens.push(Token::Here);
+            i += 1;
+        } else if b"+-*/()".contains(&c) {
+            tokens.push(Token::Op(c as char));
+            i += 1;
+        } else {
+            return Err(ExprError::Syntax(format!(
+                "unexpected character '{}'",
+                c as char
+            )));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    here: u16,
+    lookup: &'a dyn Fn(&str) -> Option<u16>,
+}
+
+impl Parser<'_> {
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Ident(w)) if w == word)
+    }
+
+    fn peek_op(&self, op: char) -> bool {
+        self.tokens.get(self.pos) == Some(&Token::Op(op))
+    }
+
+    fn take_word(&mut self, words: &[&'static str]) -> Option<&'static str> {
+        let found = words.iter().find(|w| self.peek_word(w))?;
+        self.pos += 1;
+        Some(found)
+    }
+
+    fn or(&mut self) -> Result<u16, ExprError> {
+        let mut value = self.and()?;
+        while let Some(op) = self.take_word(&["OR", "XOR"]) {
+            let rhs = self.and()?;
+            value = if op == "OR" { value | rhs } else { value ^ rhs };
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<u16, ExprError> {
+        let mut value = self.not()?;
+        while self.take_word(&["AND"]).is_some() {
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<u16, ExprError> {
+        if self.take_word(&["NOT"]).is_some() {
+            return Ok(!self.not()?);
+        }
+        self.relation()
+    }
+
+    fn relation(&mut self) -> Result<u16, ExprError> {
+        let lhs = self.sum()?;
+        let Some(op) = self.take_word(&["EQ", "NE", "LT", "LE", "GT", "GE"]) else {
+            return Ok(lhs);
+        };
+        let rhs = self.sum()?;
+        let result = match op {
+            "EQ" => lhs == rhs,
+            "NE" => lhs != rhs,
+            "LT" => lhs < rhs,
+            "LE" => lhs <= rhs,
+            "GT" => lhs > rhs,
+            _ => lhs >= rhs,
+        };
+        Ok(if result { 0xFFFF } else { 0 })
+    }
+
+    fn sum(&mut self) -> Result<u16, ExprError> {
+        let mut value = self.product()?;
+        loop {
+            if self.peek_op('+') {
+                self.pos += 1;
+                value = value.wrapping_add(self.product()?);
+            } else if self.peek_op('-') {
+                self.pos += 1;
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product_op(&self) -> Option<&'static str> {
+        if self.peek_op('*') {
+            Some("*")
+        } else if self.peek_op('/') {
+            Some("/")
+        } else {
+            ["MOD", "SHL", "SHR"]
+                .into_iter()
+                .find(|w| self.peek_word(w))
+        }
+    }
+
+    fn product(&mut self) -> Result<u16, ExprError> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.product_op() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            value = match op {
+                "*" => value.wrapping_mul(rhs),
+                "SHL" => value.checked_shl(rhs as u32).unwrap_or(0),
+                "SHR" => value.checked_shr(rhs as u32).unwrap_or(0),
+                _ if rhs == 0 => return Err(ExprError::Syntax("division by zero".into())),
+                "/" => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<u16, ExprError> {
+        if self.peek_op('-') {
+            self.pos += 1;
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.peek_op('+') {
+            self.pos += 1;
+            return self.unary();
+        }
+        if self.take_word(&["HIGH"]).is_some() {
+            return Ok(self.unary()? >> 8);
+        }
+        if self.take_word(&["LOW"]).is_some() {
+            return Ok(self.unary()? & 0xFF);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<u16, ExprError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| ExprError::Syntax("missing operand".into()))?;
+        self.pos += 1;
+        match token {
+            Token::Num(value) => Ok(value),
+            Token::Here => Ok(self.here),
+            Token::Ident(name) => (self.lookup)(&name).ok_or(ExprError::Undefined(name)),
+            Token::Op('(') => {
+                let value = self.or()?;
+                if !self.peek_op(')') {
+                    return Err(ExprError::Syntax("missing ')'".into()));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Token::Op(c) => Err(ExprError::Syntax(format!("unexpected '{}'", c))),
+        }
+    }
+}
+
+/// Evaluate `text`. `here` is the value of `$`, `lookup` resolves symbols
+/// (names are passed in upper case).
+pub fn eval(text: &str, here: u16, lookup: &dyn Fn(&str) -> Option<u16>) -> Result<u16, ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        here,
+        lookup,
+    };
+    let value = parser.or()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(ExprError::Syntax(format!(
+            "unexpected text in '{}'",
+            text.trim()
+        )));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Result<u16, ExprError> {
+        let lookup = |name: &str| match name {
+            "TEN" => Some(10),
+            "START" => Some(0x100),
+            _ => None,
+        };
+        eval(text, 0x1234, &lookup)
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("0FFh"), Ok(0xFF));
+        assert_eq!(value("101b"), Ok(5));
+        assert_eq!(value("17o"), Ok(15));
+        assert_eq!(value("17q"), Ok(15));
+        assert_eq!(value("99d"), Ok(99));
+        assert_eq!(value("'A'"), Ok(0x41));
+        assert_eq!(value("'AB'"), Ok(0x4142));
+        assert_eq!(value("''''"), Ok(0x27));
+        assert_eq!(value("$"), Ok(0x1234));
+        assert!(value("10000h").is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("2+3*4"), Ok(14));
+        assert_eq!(value("(2+3)*4"), Ok(20));
+        assert_eq!(value("10-4-3"), Ok(3));
+        assert_eq!(value("1 SHL 4 + 1"), Ok(0x11));
+        assert_eq!(value("-TEN+20"), Ok(10));
+        assert_eq!(value("HIGH START+1"), Ok(2));
+        assert_eq!(value("LOW 1234h"), Ok(0x34));
+        assert_eq!(value("17 MOD 5 * 2"), Ok(4));
+        // Relations bind tighter than NOT, which binds tighter than AND/OR.
+        assert_eq!(value("TEN EQ 10 AND 1"), Ok(1));
+        assert_eq!(value("NOT TEN EQ 10"), Ok(0));
+        assert_eq!(value("1 OR 2 AND 3"), Ok(3));
+        assert_eq!(value("6 XOR 3"), Ok(5));
+        assert_eq!(value("0-1"), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(value("NOPE+1"), Err(ExprError::Undefined("NOPE".into())));
+        assert_eq!(
+            value("1/0"),
+            Err(ExprError::Syntax("division by zero".into()))
+        );
+        assert_eq!(value("(1+2"), Err(ExprError::Syntax("missing ')'".into())));
+        assert_eq!(
+            value("1+"),
+            Err(ExprError::Syntax("missing operand".into()))
+        );
+        assert!(matches!(value("1 2"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value("'ABC'"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value("1 # 2"), Err(ExprError::Syntax(_))));
+    }
+}
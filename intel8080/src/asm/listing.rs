@@ -0,0 +1,113 @@
+// Assembler listing (.LST) output.
+//
+//    LINE  ADDR  CODE           SOURCE
+//      12  0100  31 00 24       START:  LXI SP,STACK
+//      13  = 000D               CR      EQU 0DH
+//      14  0103  3E 05        + MVI A,5
+//
+// A '+' marks lines produced by a macro expansion and an 'I' lines read from
+// an INCLUDE file. Instructions with more than four bytes (DB strings
+// mostly) continue on extra lines.
+use std::collections::BTreeMap;
+
+const BYTES_PER_LINE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineValue {
+    /// Address of the code or data emitted by the line.
+    Addr(u16),
+    /// Value given to an EQU or SET symbol.
+    Equ(u16),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListingLine {
+    pub line: usize,
+    pub value: Option<LineValue>,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    pub expansion: bool,
+    pub included: bool,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn format_listing(lines: &[ListingLine], symbols: &BTreeMap<String, u16>) -> String {
+    let mut out = String::from("  LINE  ADDR  CODE           SOURCE\n");
+    for l in lines {
+        let value = match l.value {
+            Some(LineValue::Addr(addr)) => format!("{:04X}  ", addr),
+            Some(LineValue::Equ(value)) => format!("= {:04X}", value),
+            None => "      ".to_string(),
+        };
+        let marker = if l.expansion {
+            '+'
+        } else if l.included {
+            'I'
+        } else {
+            ' '
+        };
+        let mut chunks = l.bytes.chunks(BYTES_PER_LINE);
+        let first = chunks.next().map(hex_bytes).unwrap_or_default();
+        out.push_str(&format!(
+            "{:>6}  {}{:<13}{} {}\n",
+            l.line, value, first, marker, l.text
+        ));
+        if let Some(LineValue::Addr(addr)) = l.value {
+            for (i, chunk) in chunks.enumerate() {
+                let addr = addr.wrapping_add(((i + 1) * BYTES_PER_LINE) as u16);
+                out.push_str(&format!("        {:04X}  {}\n", addr, hex_bytes(chunk)));
+            }
+        }
+    }
+    out.push_str("\nSYMBOLS\n");
+    for (name, value) in symbols {
+        out.push_str(&format!("{:04X}  {}\n", value, name));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble_with;
+
+    #[test]
+    fn listing_layout() {
+        let source = concat!(
+            "CR EQU 0Dh\n",
+            "M MACRO\n",
+            " MVI A,5\n",
+            " ENDM\n",
+            "START: LXI SP,2400h\n",
+            " M\n",
+            " INCLUDE 'x.inc'\n",
+            " DB 'HELLO',CR\n",
+        );
+        let assembly = assemble_with(source, None, |_| Ok(" NOP\n".into())).unwrap();
+        let expected = concat!(
+            "  LINE  ADDR  CODE           SOURCE\n",
+            "     1  = 000D               CR EQU 0Dh\n",
+            "     2                       M MACRO\n",
+            "     3                        MVI A,5\n",
+            "     4                        ENDM\n",
+            "     5  0000  31 00 24       START: LXI SP,2400h\n",
+            "     6                        M\n",
+            "     6  0003  3E 05        +  MVI A,5\n",
+            "     7                        INCLUDE 'x.inc'\n",
+            "     1  0005  00           I  NOP\n",
+            "     8  0006  48 45 4C 4C     DB 'HELLO',CR\n",
+            "        000A  4F 0D\n",
+            "\n",
+            "SYMBOLS\n",
+            "000D  CR\n",
+            "0000  START\n",
+        );
+        assert_eq!(assembly.listing(), expected);
+    }
+}
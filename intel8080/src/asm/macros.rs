@@ -0,0 +1,142 @@
+// Macro definitions and expansion.
+//
+//   NAME    MACRO   P1,P2
+//           LOCAL   LOOP
+//   LOOP:   DCR     P1
+//           JNZ     LOOP
+//           ENDM
+//
+// Parameters are replaced wherever they appear as a whole identifier outside
+// quotes. Names listed with LOCAL get a fresh ??nnnn name on every
+// expansion so a macro can be used more than once.
+use super::expr::{is_ident_char, is_ident_start, read_quoted};
+use super::split_operands;
+
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<String>,
+}
+
+/// Replace whole identifiers found in `names` (upper case) with their
+/// replacement, leaving quoted strings and comments alone.
+pub fn substitute(text: &str, names: &[(String, String)]) -> String {
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b';' {
+            out.push_str(&text[i..]);
+            break;
+        }
+        if let Some((_, end)) = read_quoted(bytes, i) {
+            out.push_str(&text[i..end]);
+            i = end;
+        } else if is_ident_start(c) {
+            let start = i;
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            let word = &text[start..i];
+            let upper = word.to_ascii_uppercase();
+            match names.iter().find(|(name, _)| *name == upper) {
+                Some((_, replacement)) => out.push_str(replacement),
+                None => out.push_str(word),
+            }
+        } else if c.is_ascii_digit() {
+            // Skip whole numbers so the B in 0Bh is never taken for a name.
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            out.push_str(&text[start..i]);
+        } else {
+            let len = text[i..].chars().next().map_or(1, char::len_utf8);
+            out.push_str(&text[i..i + len]);
+            i += len;
+        }
+    }
+    out
+}
+
+impl Macro {
+    /// The body with arguments and LOCAL names filled in. `serial` numbers
+    /// the local names and is advanced for each one used.
+    pub fn expand(&self, args: &[&str], serial: &mut usize) -> Result<Vec<String>, String> {
+        if args.len() > self.params.len() {
+            return Err(format!(
+                "macro takes {} arguments, {} given",
+                self.params.len(),
+                args.len()
+            ));
+        }
+        // Missing trailing arguments expand to nothing.
+        let mut names: Vec<(String, String)> = self
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.clone(), args.get(i).unwrap_or(&"").to_string()))
+            .collect();
+
+        let mut body = Vec::new();
+        for line in &self.body {
+            let (first, rest) = super::split_word(super::strip_comment(line));
+            if first.eq_ignore_ascii_case("LOCAL") {
+                for local in split_operands(rest) {
+                    *serial += 1;
+                    names.push((local.to_ascii_uppercase(), format!("??{:04}", serial)));
+                }
+            } else {
+                body.push(line);
+            }
+        }
+        Ok(body.iter().map(|line| substitute(line, &names)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn substitute_whole_identifiers_only() {
+        let names = names(&[("B", "C"), ("N", "10")]);
+        assert_eq!(substitute("\tMVI B,N", &names), "\tMVI C,10");
+        // Not inside longer names, numbers, strings or comments.
+        assert_eq!(substitute("\tMVI BN,0Bh", &names), "\tMVI BN,0Bh");
+        assert_eq!(substitute("\tDB 'B',\"N\"", &names), "\tDB 'B',\"N\"");
+        assert_eq!(substitute("\tMOV A,b ; B", &names), "\tMOV A,C ; B");
+    }
+
+    #[test]
+    fn expand_fills_in_arguments_and_locals() {
+        let mac = Macro {
+            params: vec!["REG".into(), "COUNT".into()],
+            body: vec![
+                "\tLOCAL LOOP, DONE".into(),
+                "LOOP:\tDCR REG".into(),
+                "\tJNZ LOOP ; COUNT".into(),
+                "DONE:\tDB COUNT".into(),
+            ],
+        };
+        let mut serial = 4;
+        assert_eq!(
+            mac.expand(&["D"], &mut serial).unwrap(),
+            ["??0005:\tDCR D", "\tJNZ ??0005 ; COUNT", "??0006:\tDB ",]
+        );
+        assert_eq!(serial, 6);
+        assert_eq!(
+            mac.expand(&["E", "3"], &mut serial).unwrap()[0],
+            "??0007:\tDCR E"
+        );
+        assert!(mac.expand(&["A", "B", "C"], &mut serial).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+// Shadow call stack.
+//
+// The 8080 keeps return addresses in ordinary memory, so once a program
+// goes wrong there is nothing to say how it got there. While tracking is
+// on, every CALL, RST and interrupt pushes a frame here and every RET pops
+// one, giving a backtrace at any point:
+//
+//   #0  0118 in 0116
+//   #1  010C in 0100 [interrupt]
+//   #2  0107 in ??
+//
+// A return that doesn't land where the innermost frame expects (a RET used
+// as a computed jump, or a stack rearranged by hand) is recorded as a
+// mismatch. If the return matches a frame further out, the frames in
+// between are discarded; otherwise the innermost frame is only dropped when
+// SP shows its return address was consumed.
+use crate::Intel8080;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Restart,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The CALL or RST instruction, or the instruction an interrupt
+    /// preempted.
+    pub site: u16,
+    /// Where execution went: the subroutine or the interrupt vector.
+    pub target: u16,
+    /// The return address pushed on the stack.
+    pub return_addr: u16,
+    /// SP after the return address was pushed.
+    pub sp: u16,
+}
+
+/// A return that didn't go back to the innermost frame's return address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The returning instruction.
+    pub pc: u16,
+    pub actual: u16,
+    /// The innermost frame's return address, None if no frame was open.
+    pub expected: Option<u16>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "return at {:04X} went to {:04X}, expected {:04X}",
+                self.pc, self.actual, expected
+            ),
+            None => write!(
+                f,
+                "return at {:04X} went to {:04X} with no call outstanding",
+                self.pc, self.actual
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open frames, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Every mismatched return seen so far, oldest first.
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    pub(crate) fn enter(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    // A return from `pc` to `next`, leaving SP at `sp`. Returns the frames
+    // it closed, outermost first.
+    pub(crate) fn leave(&mut self, pc: u16, next: u16, sp: u16) -> Vec<Frame> {
+        let keep = match self.frames.iter().rposition(|f| f.return_addr == next) {
+            Some(i) if i == self.frames.len() - 1 => i,
+            Some(i) => {
+                self.mismatch(pc, next);
+                i
+            }
+            None => {
+                self.mismatch(pc, next);
+                match self.frames.last() {
+                    Some(f) if sp > f.sp => self.frames.len() - 1,
+                    _ => self.frames.len(),
+                }
+            }
+        };
+        self.frames.split_off(keep)
+    }
+
+    // Undo an enter or a leave, for stepping back.
+    pub(crate) fn undo(&mut self, entered: bool, left: &[Frame]) {
+        if entered {
+            self.frames.pop();
+        }
+        self.frames.extend_from_slice(left);
+    }
+
+    fn mismatch(&mut self, pc: u16, actual: u16) {
+        self.mismatches.push(Mismatch {
+            pc,
+            actual,
+            expected: self.frames.last().map(|f| f.return_addr),
+        });
+    }
+
+    pub fn backtrace(&self, pc: u16) -> String {
+        self.backtrace_with(pc, &|_| None)
+    }
+
+    /// One line per frame, innermost first, starting from `pc`. `name`
+    /// supplies labels for the subroutines.
+    pub fn backtrace_with(&self, pc: u16, name: &dyn Fn(u16) -> Option<String>) -> String {
+        let label = |addr: u16| name(addr).unwrap_or_else(|| format!("{:04X}", addr));
+        let mut out = String::new();
+        let mut at = pc;
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Call => "",
+                FrameKind::Restart => " [rst]",
+                FrameKind::Interrupt => " [interrupt]",
+            };
+            out.push_str(&format!(
+                "#{:<2} {:04X} in {}{}\n",
+                i,
+                at,
+                label(frame.target),
+                kind
+            ));
+            at = frame.site;
+        }
+        out.push_str(&format!("#{:<2} {:04X} in ??\n", self.frames.len(), at));
+        out
+    }
+}
+
+impl Intel8080 {
+    /// Start keeping a shadow call stack, discarding any kept so far.
+    pub fn track_calls(&mut self) {
+        self.calls = Some(CallStack::new());
+    }
+
+    pub fn stop_tracking_calls(&mut self) {
+        self.calls = None;
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.calls.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn tracked(source: &str) -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.track_calls();
+        asm::run_on(cpu, source)
+    }
+
+    #[test]
+    fn calls_and_returns_nest() {
+        let program = "
+            LXI SP,2400h
+            CALL OUTER
+            HLT
+    OUTER:  CALL INNER
+            RET
+    INNER:  MVI A,1
+            RET
+        ";
+        let assembly = asm::assemble(program).unwrap();
+        let mut cpu = Intel8080::new();
+        cpu.load_image(&assembly.image).unwrap();
+        cpu.track_calls();
+        let mut depths = Vec::new();
+        while !cpu.is_halted() {
+            cpu.tick();
+            depths.push(cpu.call_stack().unwrap().depth());
+            if cpu.state().pc == 0x000D {
+                assert_eq!(
+                    cpu.call_stack().unwrap().backtrace(0x000D),
+                    "#0  000D in 000B\n#1  0007 in 0007\n#2  0003 in ??\n"
+                );
+            }
+        }
+        assert_eq!(depths, [0, 1, 2, 2, 1, 0, 0]);
+        let calls = cpu.call_stack().unwrap();
+        assert!(calls.mismatches().is_empty());
+    }
+
+    #[test]
+    fn return_after_dropping_a_frame_by_hand() {
+        // INNER throws its own return address away, so its RET goes
+        // straight back to OUTER's caller.
+        let cpu =
+            tracked("LXI SP,2400h; CALL OUTER; HLT; OUTER: CALL INNER; RET; INNER: POP H; RET");
+        let calls = cpu.call_stack().unwrap();
+        assert_eq!(
+            calls.mismatches(),
+            &[Mismatch {
+                pc: 0x000C,
+                actual: 0x0006,
+                expected: Some(0x000A),
+            }]
+        );
+        assert_eq!(
+            calls.mismatches()[0].to_string(),
+            "return at 000C went to 0006, expected 000A"
+        );
+        assert_eq!(calls.depth(), 0);
+    }
+
+    #[test]
+    fn unmatched_return_drops_a_frame_only_when_sp_moved_past_it() {
+        let frame = Frame {
+            kind: FrameKind::Call,
+            site: 0x0100,
+            target: 0x0200,
+            return_addr: 0x0103,
+            sp: 0x23FE,
+        };
+        let mut calls = CallStack::new();
+        calls.enter(frame);
+        // A RET used as a computed jump, from a value pushed below the frame.
+        assert!(calls.leave(0x0210, 0x0300, 0x23FE).is_empty());
+        assert_eq!(calls.depth(), 1);
+        assert_eq!(calls.leave(0x0310, 0x0400, 0x2400), [frame]);
+        assert_eq!(calls.depth(), 0);
+
+        assert!(calls.leave(0x0410, 0x0500, 0x2402).is_empty());
+        assert_eq!(
+            calls.mismatches()[2].to_string(),
+            "return at 0410 went to 0500 with no call outstanding"
+        );
+        assert_eq!(calls.mismatches().len(), 3);
+    }
+}
@@ -0,0 +1,282 @@
+// Code and data coverage.
+//
+// Every byte of memory collects flags while a Coverage is attached: part of
+// an executed instruction (and whether an instruction started there), read
+// as data, written as data. Per-opcode counts show which arms of
+// `Intel8080::execute` have run.
+//
+// Results from several runs, such as one CPU per test, can be added
+// together with `merge`.
+//
+// The annotated listing marks each line with X, R and W for executed, read
+// and written bytes:
+//
+//   X--  0100  31 00 20  LXI SP,2000h
+//   -RW  0300            DB 00h,00h
+//   ---  0302            DB 0FFh,0FFh,0FFh,0FFh,0FFh,0FFh,0FFh,0FFh
+//
+// Bytes that no executed instruction started at are listed as data.
+use crate::disasm::{self, Operand};
+use crate::{Intel8080, MEMORY_SIZE};
+use std::ops::RangeInclusive;
+
+const EXECUTED: u8 = 0b0001;
+const START: u8 = 0b0010;
+const READ: u8 = 0b0100;
+const WRITE: u8 = 0b1000;
+
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    bytes: Vec<u8>,
+    opcodes: [u64; 256],
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+// The opcode with its register operands, such as "MOV A,M" or "LXI H".
+fn opcode_name(op: u8) -> String {
+    let instr = disasm::decode(&[op], 0);
+    let regs: Vec<String> = instr
+        .operands
+        .iter()
+        .filter(|o| matches!(o, Operand::Reg(_) | Operand::Rst(_)))
+        .map(|o| o.to_string())
+        .collect();
+    if regs.is_empty() {
+        instr.mnemonic.to_string()
+    } else {
+        format!("{} {}", instr.mnemonic, regs.join(","))
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            bytes: vec![0; MEMORY_SIZE],
+            opcodes: [0; 256],
+        }
+    }
+
+    /// Forget everything recorded so far.
+    pub fn clear(&mut self) {
+        self.bytes.fill(0);
+        self.opcodes = [0; 256];
+    }
+
+    pub(crate) fn record_instruction(&mut self, pc: u16, op: u8) {
+        self.opcodes[op as usize] += 1;
+        self.bytes[pc as usize] |= START;
+        for i in 0..disasm::instruction_length(op) {
+            self.bytes[pc.wrapping_add(i as u16) as usize] |= EXECUTED;
+        }
+    }
+
+    /// Add everything `other` recorded to this coverage.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (flags, other) in self.bytes.iter_mut().zip(&other.bytes) {
+            *flags |= other;
+        }
+        for (count, other) in self.opcodes.iter_mut().zip(&other.opcodes) {
+            *count += other;
+        }
+    }
+
+    pub(crate) fn record_read(&mut self, addr: u16) {
+        self.bytes[addr as usize] |= READ;
+    }
+
+    pub(crate) fn record_write(&mut self, addr: u16) {
+        self.bytes[addr as usize] |= WRITE;
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.bytes[addr as usize] & EXECUTED != 0
+    }
+
+    pub fn is_read(&self, addr: u16) -> bool {
+        self.bytes[addr as usize] & READ != 0
+    }
+
+    pub fn is_written(&self, addr: u16) -> bool {
+        self.bytes[addr as usize] & WRITE != 0
+    }
+
+    /// How many times instructions with opcode `op` executed.
+    pub fn opcode_count(&self, op: u8) -> u64 {
+        self.opcodes[op as usize]
+    }
+
+    fn marks(&self, addr: u16) -> String {
+        let flags = self.bytes[addr as usize];
+        [(EXECUTED, 'X'), (READ, 'R'), (WRITE, 'W')]
+            .iter()
+            .map(|&(bit, c)| if flags & bit != 0 { c } else { '-' })
+            .collect()
+    }
+
+    /// Bytes, executed, read, written and untouched counts for each range.
+    pub fn summary(&self, ranges: &[RangeInclusive<u16>]) -> String {
+        let mut out = String::from(
+            "REGION       BYTES        EXECUTED            READ         WRITTEN  UNTOUCHED\n",
+        );
+        for range in ranges {
+            let flags = &self.bytes[*range.start() as usize..=*range.end() as usize];
+            let count = |bit: u8| flags.iter().filter(|f| *f & bit != 0).count();
+            let total = flags.len();
+            let column = |n: usize| format!("{:>7} {:>6.1}%", n, percent(n, total));
+            out.push_str(&format!(
+                "{:04X}-{:04X}  {:>5}  {}  {}  {}  {:>9}\n",
+                range.start(),
+                range.end(),
+                total,
+                column(count(EXECUTED)),
+                column(count(READ)),
+                column(count(WRITE)),
+                flags.iter().filter(|f| **f == 0).count()
+            ));
+        }
+        out
+    }
+
+    /// Which opcodes `execute` ran and how often, then the documented
+    /// opcodes that never ran.
+    pub fn opcode_report(&self) -> String {
+        let documented: Vec<u8> = (0..=u8::MAX)
+            .filter(|op| !disasm::is_undocumented(*op))
+            .collect();
+        let executed = documented
+            .iter()
+            .filter(|op| self.opcodes[**op as usize] > 0)
+            .count();
+        let mut out = format!(
+            "{} of {} documented opcodes executed ({:.1}%)\n\n  OP  INSTRUCTION        COUNT\n",
+            executed,
+            documented.len(),
+            percent(executed, documented.len())
+        );
+        for op in 0..=u8::MAX {
+            let count = self.opcodes[op as usize];
+            if count > 0 {
+                out.push_str(&format!(
+                    "  {:02X}  {:<10} {:>12}\n",
+                    op,
+                    opcode_name(op),
+                    count
+                ));
+            }
+        }
+        out.push_str("\nNOT EXECUTED\n");
+        for op in documented {
+            if self.opcodes[op as usize] == 0 {
+                out.push_str(&format!("  {:02X}  {}\n", op, opcode_name(op)));
+            }
+        }
+        out
+    }
+
+    /// Disassemble `range`, marking every line with its coverage.
+    /// Instructions are decoded where an executed instruction started;
+    /// everything else is shown as DB lines.
+    pub fn listing(&self, mem: &[u8], range: RangeInclusive<u16>) -> String {
+        let mut out = String::new();
+        let end = *range.end() as usize;
+        let mut addr = *range.start() as usize;
+        while addr <= end {
+            if self.bytes[addr] & START != 0 {
+                let instr = disasm::decode(mem, addr as u16);
+                out.push_str(&format!(
+                    "{}  {}\n",
+                    self.marks(addr as u16),
+                    disasm::format_line(&instr)
+                ));
+                addr += instr.len as usize;
+                continue;
+            }
+            // A run of data bytes with the same marks.
+            let flags = self.bytes[addr];
+            let mut run = vec![mem[addr]];
+            while run.len() < DATA_PER_LINE
+                && addr + run.len() <= end
+                && self.bytes[addr + run.len()] == flags
+            {
+                run.push(mem[addr + run.len()]);
+            }
+            let values: Vec<String> = run.iter().map(|b| disasm::hex8(*b)).collect();
+            out.push_str(&format!(
+                "{}  {:04X}            DB {}\n",
+                self.marks(addr as u16),
+                addr,
+                values.join(",")
+            ));
+            addr += run.len();
+        }
+        out
+    }
+}
+
+impl Intel8080 {
+    pub fn attach_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn detach_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn covered(source: &str) -> Coverage {
+        let mut cpu = Intel8080::new();
+        cpu.attach_coverage(Coverage::new());
+        let mut cpu = asm::run_on(cpu, source);
+        cpu.detach_coverage().unwrap()
+    }
+
+    #[test]
+    fn merge_adds_runs_together() {
+        let mut total = covered("MVI A,5; ADI 3; HLT");
+        total.merge(&covered("MVI A,1; STA 0300h; HLT"));
+        assert_eq!(total.opcode_count(0x3e), 2);
+        assert_eq!(total.opcode_count(0xc6), 1);
+        assert_eq!(total.opcode_count(0x32), 1);
+        assert_eq!(total.opcode_count(0x76), 2);
+        assert!(total.is_written(0x300));
+        assert!(total.is_executed(4));
+    }
+
+    #[test]
+    fn failed_opcode_is_not_covered() {
+        let mut cpu = Intel8080::new();
+        cpu.attach_coverage(Coverage::new());
+        cpu.load_at(0, &[0x3e, 0x01, 0x3c]).unwrap();
+        cpu.tick();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.tick()));
+        assert!(result.is_err());
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!(coverage.opcode_count(0x3e), 1);
+        assert_eq!(coverage.opcode_count(0x3c), 0);
+        assert!(!coverage.is_executed(2));
+    }
+}
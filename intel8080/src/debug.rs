@@ -0,0 +1,438 @@
+// Breakpoints, watchpoints and the debugging run loop.
+//
+// Execution breakpoints stop before the instruction at their address runs.
+// Port breakpoints likewise stop before the IN or OUT that names their
+// port. Watchpoints stop after the instruction that touched the watched
+// memory has completed, the way hardware watchpoints report. Each kind may
+// carry a condition that must hold for it to trigger.
+use crate::Intel8080;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+mod condition;
+
+pub use condition::{Condition, ConditionError};
+
+const OPCODE_IN: u8 = 0xdb;
+const OPCODE_OUT: u8 = 0xd3;
+
+/// The direction of a single memory or port access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// A memory read or an IN.
+    Read,
+    /// A memory write or an OUT.
+    Write,
+}
+
+/// Which accesses a watchpoint or port breakpoint reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            WatchKind::Read => access == Access::Read,
+            WatchKind::Write => access == Access::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortBreakpoint {
+    pub port: u8,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+}
+
+/// Why `Intel8080::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Execution reached a breakpoint; the instruction at `addr` has not
+    /// run yet.
+    Breakpoint(u16),
+    /// The last instruction accessed a watched byte. `value` is the byte
+    /// read, or the byte written; `kind` is what the watchpoint that
+    /// triggered was watching for.
+    Watchpoint {
+        addr: u16,
+        access: Access,
+        kind: WatchKind,
+        value: u8,
+    },
+    /// The next instruction is an IN or OUT on a watched port.
+    Port {
+        port: u8,
+        access: Access,
+    },
+    Halted,
+    /// The instruction budget ran out.
+    StepLimit,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint(addr) => write!(f, "breakpoint at {:04X}", addr),
+            StopReason::Watchpoint {
+                addr,
+                access: Access::Read,
+                value,
+                ..
+            } => write!(f, "watchpoint: read {:02X} from {:04X}", value, addr),
+            StopReason::Watchpoint {
+                addr,
+                access: Access::Write,
+                value,
+                ..
+            } => write!(f, "watchpoint: wrote {:02X} to {:04X}", value, addr),
+            StopReason::Port {
+                port,
+                access: Access::Read,
+            } => write!(f, "IN from port {:02X}", port),
+            StopReason::Port {
+                port,
+                access: Access::Write,
+            } => write!(f, "OUT to port {:02X}", port),
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::StepLimit => write!(f, "step limit reached"),
+        }
+    }
+}
+
+/// Everything the run loop should stop on. Adding a breakpoint where one
+/// already exists replaces its condition.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    ports: Vec<PortBreakpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty() && self.ports.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn add(&mut self, addr: u16, condition: Option<Condition>) {
+        self.remove(addr);
+        self.breakpoints.push(Breakpoint { addr, condition });
+        self.breakpoints.sort_by_key(|b| b.addr);
+    }
+
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.addr != addr);
+        self.breakpoints.len() != len
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|b| b.addr == addr)
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watch(
+        &mut self,
+        range: RangeInclusive<u16>,
+        kind: WatchKind,
+        condition: Option<Condition>,
+    ) {
+        self.remove_watch(range.clone(), kind);
+        self.watchpoints.push(Watchpoint {
+            range,
+            kind,
+            condition,
+        });
+    }
+
+    pub fn remove_watch(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| !(w.range == range && w.kind == kind));
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_port(&mut self, port: u8, kind: WatchKind, condition: Option<Condition>) {
+        self.remove_port(port, kind);
+        self.ports.push(PortBreakpoint {
+            port,
+            kind,
+            condition,
+        });
+    }
+
+    pub fn remove_port(&mut self, port: u8, kind: WatchKind) -> bool {
+        let len = self.ports.len();
+        self.ports.retain(|p| !(p.port == port && p.kind == kind));
+        self.ports.len() != len
+    }
+
+    pub fn ports(&self) -> &[PortBreakpoint] {
+        &self.ports
+    }
+}
+
+fn holds(condition: &Option<Condition>, cpu: &Intel8080) -> bool {
+    condition.as_ref().is_none_or(|c| c.eval(cpu))
+}
+
+impl Intel8080 {
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// Execute up to `limit` instructions, stopping early at a breakpoint,
+    /// watchpoint or HLT. Breakpoints at the starting PC are ignored so
+    /// that resuming from one makes progress.
+    pub fn run(&mut self, limit: usize) -> StopReason {
+        self.stop = None;
+        for i in 0..limit {
+            if self.halted {
+                return StopReason::Halted;
+            }
+            if i > 0 {
+                if let Some(reason) = self.breakpoint_hit() {
+                    return reason;
+                }
+            }
+            self.tick();
+            if let Some(reason) = self.stop.take() {
+                return reason;
+            }
+        }
+        if self.halted {
+            StopReason::Halted
+        } else {
+            StopReason::StepLimit
+        }
+    }
+
+    /// The breakpoint or port breakpoint, if any, that stops the
+    /// instruction at PC from executing. `run` checks this before every
+    /// instruction but the first.
+    pub fn breakpoint_hit(&self) -> Option<StopReason> {
+        if let Some(bp) = self.breakpoints.get(self.pc) {
+            if holds(&bp.condition, self) {
+                return Some(StopReason::Breakpoint(self.pc));
+            }
+        }
+        let access = match self.memory[self.pc as usize] {
+            OPCODE_IN => Access::Read,
+            OPCODE_OUT => Access::Write,
+            _ => return None,
+        };
+        let port = self.memory[self.pc.wrapping_add(1) as usize];
+        self.breakpoints
+            .ports
+            .iter()
+            .any(|p| p.port == port && p.kind.matches(access) && holds(&p.condition, self))
+            .then_some(StopReason::Port { port, access })
+    }
+
+    // Called by the memory accessors. Only the first hit of an instruction
+    // is kept.
+    pub(crate) fn check_watchpoints(&mut self, addr: u16, access: Access, value: u8) {
+        if self.stop.is_some() {
+            return;
+        }
+        let hit = self.breakpoints.watchpoints.iter().find(|w| {
+            w.range.contains(&addr) && w.kind.matches(access) && holds(&w.condition, self)
+        });
+        if let Some(w) = hit {
+            self.stop = Some(StopReason::Watchpoint {
+                addr,
+                access,
+                kind: w.kind,
+                value,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn load(source: &str) -> Intel8080 {
+        let assembly = asm::assemble(source).unwrap();
+        let mut cpu = Intel8080::new();
+        cpu.load_image(&assembly.image).unwrap();
+        cpu.set_pc(0);
+        cpu
+    }
+
+    // Reads 2400h at 0003, writes 5 to it at 0006.
+    const MEMORY: &str = "
+        LXI H,2400h
+        MOV A,M
+        MVI A,5
+        MOV M,A
+        HLT
+    ";
+
+    fn condition(text: &str) -> Option<Condition> {
+        Some(Condition::parse(text).unwrap())
+    }
+
+    #[test]
+    fn read_watchpoint() {
+        let mut cpu = load(MEMORY);
+        cpu.breakpoints_mut()
+            .add_watch(0x2400..=0x2400, WatchKind::Read, None);
+        let reason = cpu.run(100);
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                addr: 0x2400,
+                access: Access::Read,
+                kind: WatchKind::Read,
+                value: 0,
+            }
+        );
+        // Reported once the instruction has finished.
+        assert_eq!(cpu.state().pc, 4);
+        assert_eq!(cpu.run(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        let mut cpu = load(MEMORY);
+        cpu.breakpoints_mut()
+            .add_watch(0x23FF..=0x2401, WatchKind::Write, None);
+        let reason = cpu.run(100);
+        assert_eq!(
+            reason,
+            StopReason::Watchpoint {
+                addr: 0x2400,
+                access: Access::Write,
+                kind: WatchKind::Write,
+                value: 5,
+            }
+        );
+        assert_eq!(reason.to_string(), "watchpoint: wrote 05 to 2400");
+        assert_eq!(cpu.state().pc, 7);
+    }
+
+    #[test]
+    fn access_watchpoint_stops_on_both() {
+        let mut cpu = load(MEMORY);
+        cpu.breakpoints_mut()
+            .add_watch(0x2400..=0x2400, WatchKind::ReadWrite, None);
+        assert!(matches!(
+            cpu.run(100),
+            StopReason::Watchpoint {
+                access: Access::Read,
+                kind: WatchKind::ReadWrite,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cpu.run(100),
+            StopReason::Watchpoint {
+                access: Access::Write,
+                kind: WatchKind::ReadWrite,
+                ..
+            }
+        ));
+        assert_eq!(cpu.run(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn watchpoint_condition() {
+        let mut cpu = load(MEMORY);
+        let bps = cpu.breakpoints_mut();
+        bps.add_watch(0x2400..=0x2400, WatchKind::ReadWrite, condition("A == 5"));
+        assert!(matches!(
+            cpu.run(100),
+            StopReason::Watchpoint {
+                access: Access::Write,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn port_breakpoints() {
+        let mut cpu = load("\tMVI A,1\n\tOUT 10h\n\tOUT 20h\n\tHLT\n");
+        let bps = cpu.breakpoints_mut();
+        bps.add_port(0x10, WatchKind::Read, None);
+        bps.add_port(0x20, WatchKind::Write, None);
+        let reason = cpu.run(100);
+        assert_eq!(
+            reason,
+            StopReason::Port {
+                port: 0x20,
+                access: Access::Write,
+            }
+        );
+        assert_eq!(reason.to_string(), "OUT to port 20");
+        // The OUT has not run yet.
+        assert_eq!(cpu.state().pc, 4);
+        assert_eq!(cpu.run(100), StopReason::Halted);
+
+        let mut cpu = load("\tMVI A,1\n\tOUT 10h\n\tHLT\n");
+        cpu.breakpoints_mut()
+            .add_port(0x10, WatchKind::ReadWrite, condition("A == 2"));
+        assert_eq!(cpu.run(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn run_resumes_from_a_breakpoint() {
+        let mut cpu = load("\tMVI B,2\nLOOP:\tDCR B\n\tJNZ LOOP\n\tHLT\n");
+        cpu.breakpoints_mut().add(0x0002, None);
+        assert_eq!(cpu.run(100), StopReason::Breakpoint(2));
+        assert_eq!(cpu.state().b, 2);
+        // The breakpoint at the starting PC doesn't stop it again at once.
+        assert_eq!(cpu.run(100), StopReason::Breakpoint(2));
+        assert_eq!(cpu.state().b, 1);
+        assert_eq!(cpu.run(1), StopReason::StepLimit);
+        assert_eq!(cpu.run(100), StopReason::Halted);
+        assert_eq!(cpu.state().b, 0);
+    }
+
+    #[test]
+    fn breakpoint_condition() {
+        let mut cpu = load("\tMVI B,3\nLOOP:\tDCR B\n\tJNZ LOOP\n\tHLT\n");
+        cpu.breakpoints_mut().add(0x0002, condition("B == 1"));
+        assert_eq!(cpu.run(100), StopReason::Breakpoint(2));
+        assert_eq!(cpu.state().b, 1);
+    }
+}
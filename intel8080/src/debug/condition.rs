@@ -0,0 +1,408 @@
+// Breakpoint conditions.
+//
+//   A == 0x20 && HL > 2400h
+//   !Z || (B != 0 && CY)
+//   [HL] == 0 && [2400h] != A
+//
+// Names are registers (A B C D E H L, the pairs BC DE HL, SP, PC and F for
+// the flag byte) or single flags (S Z AC P CY), all case-insensitive.
+// Numbers are decimal, 0x-prefixed or h-suffixed hex. Brackets read the
+// byte of memory at the address inside them. Comparisons are unsigned
+// 16-bit; a bare value is true when it is not zero.
+use crate::{CpuState, Intel8080};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionError {
+    UnknownName(String),
+    Syntax(String),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConditionError::UnknownName(name) => write!(f, "unknown register '{}'", name),
+            ConditionError::Syntax(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Name {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    F,
+    S,
+    Z,
+    AC,
+    P,
+    CY,
+}
+
+const NAMES: [(&str, Name); 18] = [
+    ("a", Name::A),
+    ("b", Name::B),
+    ("c", Name::C),
+    ("d", Name::D),
+    ("e", Name::E),
+    ("h", Name::H),
+    ("l", Name::L),
+    ("bc", Name::BC),
+    ("de", Name::DE),
+    ("hl", Name::HL),
+    ("sp", Name::SP),
+    ("pc", Name::PC),
+    ("f", Name::F),
+    ("s", Name::S),
+    ("z", Name::Z),
+    ("ac", Name::AC),
+    ("p", Name::P),
+    ("cy", Name::CY),
+];
+
+impl Name {
+    fn value(self, state: &CpuState) -> u16 {
+        let pair = |hi: u8, lo: u8| u16::from_be_bytes([hi, lo]);
+        let flag = |bit: u8| ((state.flags >> bit) & 1) as u16;
+        match self {
+            Name::A => state.a as u16,
+            Name::B => state.b as u16,
+            Name::C => state.c as u16,
+            Name::D => state.d as u16,
+            Name::E => state.e as u16,
+            Name::H => state.h as u16,
+            Name::L => state.l as u16,
+            Name::BC => pair(state.b, state.c),
+            Name::DE => pair(state.d, state.e),
+            Name::HL => pair(state.h, state.l),
+            Name::SP => state.sp,
+            Name::PC => state.pc,
+            Name::F => state.flags as u16,
+            Name::S => flag(7),
+            Name::Z => flag(6),
+            Name::AC => flag(4),
+            Name::P => flag(2),
+            Name::CY => flag(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Num(u16),
+    Name(Name),
+    Not(Box<Node>),
+    Memory(Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, state: &CpuState, memory: &[u8]) -> u16 {
+        let eval = |node: &Node| node.eval(state, memory);
+        match self {
+            Node::Num(value) => *value,
+            Node::Name(name) => name.value(state),
+            Node::Not(node) => (eval(node) == 0) as u16,
+            Node::Memory(addr) => memory[eval(addr) as usize] as u16,
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = eval(lhs);
+                // Short-circuit like the operators they are named after.
+                let result = match *op {
+                    "&&" => lhs != 0 && eval(rhs) != 0,
+                    "||" => lhs != 0 || eval(rhs) != 0,
+                    "==" => lhs == eval(rhs),
+                    "!=" => lhs != eval(rhs),
+                    "<" => lhs < eval(rhs),
+                    "<=" => lhs <= eval(rhs),
+                    ">" => lhs > eval(rhs),
+                    _ => lhs >= eval(rhs),
+                };
+                result as u16
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(u16),
+    Name(Name),
+    Op(&'static str),
+}
+
+// Longest operators first so "<=" isn't read as "<".
+const OPERATORS: [&str; 13] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]",
+];
+
+fn parse_number(word: &str) -> Option<u16> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ConditionError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let token =
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    Token::Num(parse_number(word).ok_or_else(|| {
+                        ConditionError::Syntax(format!("invalid number '{}'", word))
+                    })?)
+                } else {
+                    let lower = word.to_ascii_lowercase();
+                    let (_, name) = NAMES
+                        .iter()
+                        .find(|(n, _)| *n == lower)
+                        .ok_or_else(|| ConditionError::UnknownName(word.to_string()))?;
+                    Token::Name(*name)
+                };
+            tokens.push(token);
+            rest = &rest[end..];
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            return Err(ConditionError::Syntax(format!(
+                "unexpected character '{}'",
+                c
+            )));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn take_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, ConditionError> {
+        let mut node = self.and()?;
+        while let Some(op) = self.take_op(&["||"]) {
+            node = Node::Binary(op, Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, ConditionError> {
+        let mut node = self.comparison()?;
+        while let Some(op) = self.take_op(&["&&"]) {
+            node = Node::Binary(op, Box::new(node), Box::new(self.comparison()?));
+        }
+        Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ConditionError> {
+        let lhs = self.unary()?;
+        match self.take_op(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some(op) => Ok(Node::Binary(op, Box::new(lhs), Box::new(self.unary()?))),
+            None => Ok(lhs),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ConditionError> {
+        if self.take_op(&["!"]).is_some() {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| ConditionError::Syntax("missing operand".into()))?;
+        self.pos += 1;
+        match token {
+            Token::Num(value) => Ok(Node::Num(value)),
+            Token::Name(name) => Ok(Node::Name(name)),
+            Token::Op("(") => {
+                let node = self.or()?;
+                if self.take_op(&[")"]).is_none() {
+                    return Err(ConditionError::Syntax("missing ')'".into()));
+                }
+                Ok(node)
+            }
+            Token::Op("[") => {
+                let addr = self.or()?;
+                if self.take_op(&["]"]).is_none() {
+                    return Err(ConditionError::Syntax("missing ']'".into()));
+                }
+                Ok(Node::Memory(Box::new(addr)))
+            }
+            Token::Op(op) => Err(ConditionError::Syntax(format!("unexpected '{}'", op))),
+        }
+    }
+}
+
+/// A parsed condition, evaluated against the CPU state each time its
+/// breakpoint or watchpoint is reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    text: String,
+    root: Node,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let root = parser.or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(ConditionError::Syntax(format!(
+                "unexpected text in '{}'",
+                text.trim()
+            )));
+        }
+        Ok(Self {
+            text: text.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, cpu: &Intel8080) -> bool {
+        self.root.eval(&cpu.state(), cpu.memory()) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        let mut state = cpu.state();
+        state.a = 0x20;
+        (state.b, state.c) = (0x01, 0x02);
+        (state.h, state.l) = (0x24, 0x10);
+        state.sp = 0x2000;
+        state.flags = 0b0100_0011;
+        cpu.set_state(&state);
+        cpu.memory_mut()[0x2410] = 0x7F;
+        cpu
+    }
+
+    fn holds(text: &str) -> bool {
+        Condition::parse(text).unwrap().eval(&cpu())
+    }
+
+    #[test]
+    fn names_and_numbers() {
+        assert!(holds("A == 0x20"));
+        assert!(holds("a == 20h"));
+        assert!(holds("A == 32"));
+        assert!(holds(
+            "BC == 0102h && HL == 0x2410 && SP == 2000h && PC == 0"
+        ));
+        assert!(holds("F == 43h"));
+        assert!(holds("Z && CY && !S && !AC && !P"));
+        assert!(holds("hl > 2400h"));
+        assert!(!holds("B >= 2"));
+    }
+
+    #[test]
+    fn memory_dereference() {
+        assert!(holds("[HL] == 7Fh"));
+        assert!(holds("[2410h] == [HL]"));
+        assert!(holds("[BC] == 0 && [HL] > A"));
+        assert!(holds("([HL]) == [(0x2410)]"));
+        assert!(holds("[HL]"));
+        assert!(!holds("[SP]"));
+    }
+
+    #[test]
+    fn precedence() {
+        // && binds tighter than ||.
+        assert!(holds("A == 0 && B == 0 || C == 2"));
+        assert!(holds("C == 2 || A == 0 && B == 0"));
+        assert!(!holds("(C == 2 || A == 0) && B == 0"));
+        // ! applies to the operand only.
+        assert!(holds("!B == 0"));
+        assert!(holds("!(B == 0)"));
+        assert!(holds("!!A"));
+    }
+
+    #[test]
+    fn display_keeps_the_text() {
+        let condition = Condition::parse("  A == 1 ").unwrap();
+        assert_eq!(condition.to_string(), "A == 1");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text| Condition::parse(text).unwrap_err();
+        assert_eq!(error("IX == 0"), ConditionError::UnknownName("IX".into()));
+        assert_eq!(
+            error("A == 0xZZ"),
+            ConditionError::Syntax("invalid number '0xZZ'".into())
+        );
+        assert_eq!(
+            error("(A == 1"),
+            ConditionError::Syntax("missing ')'".into())
+        );
+        assert_eq!(
+            error("[HL == 1"),
+            ConditionError::Syntax("missing ']'".into())
+        );
+        assert_eq!(
+            error("A =="),
+            ConditionError::Syntax("missing operand".into())
+        );
+        assert_eq!(error(""), ConditionError::Syntax("missing operand".into()));
+        assert_eq!(
+            error("A == 1 B"),
+            ConditionError::Syntax("unexpected text in 'A == 1 B'".into())
+        );
+        assert_eq!(
+            error("A + 1"),
+            ConditionError::Syntax("unexpected character '+'".into())
+        );
+        assert_eq!(
+            error("&& A"),
+            ConditionError::Syntax("unexpected '&&'".into())
+        );
+        assert_eq!(error("IX").to_string(), "unknown register 'IX'");
+    }
+}
@@ -0,0 +1,306 @@
+// Intel-syntax disassembler.
+//
+// Numbers are printed the way Intel's assemblers expect them: hexadecimal
+// with an `h` suffix, and a leading zero when the first digit is a letter
+// (`0FFh`), so a listing can be fed straight back into an assembler.
+pub mod flow;
+
+use crate::Intel8080;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+pub(crate) const REGS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+pub(crate) const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+pub(crate) const PAIRS_PSW: [&str; 4] = ["B", "D", "H", "PSW"];
+pub(crate) const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+pub(crate) const ALU_IMM: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+pub(crate) const JUMPS: [&str; 8] = ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+pub(crate) const CALLS: [&str; 8] = ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+pub(crate) const RETURNS: [&str; 8] = ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(&'static str),
+    Imm8(u8),
+    Imm16(u16),
+    // A 16-bit operand that names a memory location (jump and call targets,
+    // LDA/STA/LHLD/SHLD addresses), as opposed to plain data.
+    Addr(u16),
+    Rst(u8),
+}
+
+pub fn hex8(value: u8) -> String {
+    let s = format!("{:02X}h", value);
+    if s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", s)
+    } else {
+        s
+    }
+}
+
+pub fn hex16(value: u16) -> String {
+    let s = format!("{:04X}h", value);
+    if s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}", s)
+    } else {
+        s
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(name) => write!(f, "{}", name),
+            Operand::Imm8(value) => write!(f, "{}", hex8(*value)),
+            Operand::Imm16(value) | Operand::Addr(value) => write!(f, "{}", hex16(*value)),
+            Operand::Rst(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Execution carries on with the next instruction.
+    Next,
+    /// Unconditional jump.
+    Jump(u16),
+    /// Conditional jump: either the target or the next instruction.
+    Branch(u16),
+    /// CALL, conditional calls and RST. Execution is expected to come back
+    /// to the next instruction.
+    Call(u16),
+    Return,
+    ConditionalReturn,
+    /// PCHL: the target is only known at run time.
+    Indirect,
+    /// HLT: execution resumes at the next instruction after an interrupt.
+    Halt,
+}
+
+impl Flow {
+    /// Whether the instruction can be followed by the one after it.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Flow::Jump(_) | Flow::Return | Flow::Indirect)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub len: u8,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    bytes: [u8; 3],
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Address of the instruction that follows this one.
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len as u16)
+    }
+
+    /// How the instruction affects the flow of control.
+    pub fn flow(&self) -> Flow {
+        let target = self.address_operand().unwrap_or(0);
+        match self.mnemonic {
+            "JMP" => Flow::Jump(target),
+            "JNZ" | "JZ" | "JNC" | "JC" | "JPO" | "JPE" | "JP" | "JM" => Flow::Branch(target),
+            "CALL" | "CNZ" | "CZ" | "CNC" | "CC" | "CPO" | "CPE" | "CP" | "CM" => {
+                Flow::Call(target)
+            }
+            "RST" => Flow::Call((self.opcode & 0b0011_1000) as u16),
+            "RET" => Flow::Return,
+            "RNZ" | "RZ" | "RNC" | "RC" | "RPO" | "RPE" | "RP" | "RM" => Flow::ConditionalReturn,
+            "PCHL" => Flow::Indirect,
+            "HLT" => Flow::Halt,
+            _ => Flow::Next,
+        }
+    }
+
+    /// The memory address named by the instruction, if any.
+    pub fn address_operand(&self) -> Option<u16> {
+        self.operands.iter().find_map(|op| match op {
+            Operand::Addr(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+
+    /// Format the instruction, letting `name` replace address operands with
+    /// a symbolic name.
+    pub fn format_with<F: Fn(u16) -> Option<String>>(&self, name: F) -> String {
+        let mut out = self.mnemonic.to_string();
+        for (i, op) in self.operands.iter().enumerate() {
+            out.push(if i == 0 { ' ' } else { ',' });
+            match op {
+                Operand::Addr(addr) => match name(*addr) {
+                    Some(label) => out.push_str(&label),
+                    None => out.push_str(&op.to_string()),
+                },
+                _ => out.push_str(&op.to_string()),
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format_with(|_| None))
+    }
+}
+
+/// Number of bytes taken by the instruction starting with `op`.
+pub fn instruction_length(op: u8) -> u8 {
+    match op {
+        // MVI, ADI..CPI, OUT, IN
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e => 2,
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => 2,
+        0xd3 | 0xdb => 2,
+        // LXI, SHLD, LHLD, STA, LDA
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a => 3,
+        // JMP, Jcc, CALL, Ccc
+        0xc3 | 0xcd => 3,
+        _ if op & 0b1100_0111 == 0b1100_0010 => 3,
+        _ if op & 0b1100_0111 == 0b1100_0100 => 3,
+        _ => 1,
+    }
+}
+
+// Undocumented opcodes are shown as data so the listing still assembles.
+pub(crate) fn is_undocumented(op: u8) -> bool {
+    matches!(
+        op,
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd
+    )
+}
+
+fn read(mem: &[u8], addr: u16) -> u8 {
+    mem.get(addr as usize).copied().unwrap_or(0)
+}
+
+/// Decode the instruction at `addr`. Bytes past the end of `mem` read as 0.
+pub fn decode(mem: &[u8], addr: u16) -> Instruction {
+    let op = read(mem, addr);
+    let len = if is_undocumented(op) {
+        1
+    } else {
+        instruction_length(op)
+    };
+    let mut bytes = [op, 0, 0];
+    for (i, byte) in bytes.iter_mut().enumerate().take(len as usize).skip(1) {
+        *byte = read(mem, addr.wrapping_add(i as u16));
+    }
+    let d8 = bytes[1];
+    let d16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+    // Most opcodes are laid out as xxDDDSSS or xxRPxxxx.
+    let ddd = ((op >> 3) & 0b111) as usize;
+    let sss = (op & 0b111) as usize;
+    let rp = ((op >> 4) & 0b11) as usize;
+    let (dst, src) = (REGS[ddd], REGS[sss]);
+
+    use Operand::*;
+    let (mnemonic, operands): (&'static str, Vec<Operand>) = match op {
+        _ if is_undocumented(op) => ("DB", vec![Imm8(op)]),
+        0x00 => ("NOP", vec![]),
+        0x76 => ("HLT", vec![]),
+        0x40..=0x7f => ("MOV", vec![Reg(dst), Reg(src)]),
+        0x80..=0xbf => (ALU[ddd], vec![Reg(src)]),
+        _ if op & 0b1100_0111 == 0b1100_0110 => (ALU_IMM[ddd], vec![Imm8(d8)]),
+        _ if op & 0b1100_1111 == 0b0000_0001 => ("LXI", vec![Reg(PAIRS[rp]), Imm16(d16)]),
+        _ if op & 0b1100_1111 == 0b0000_0011 => ("INX", vec![Reg(PAIRS[rp])]),
+        _ if op & 0b1100_1111 == 0b0000_1001 => ("DAD", vec![Reg(PAIRS[rp])]),
+        _ if op & 0b1100_1111 == 0b0000_1011 => ("DCX", vec![Reg(PAIRS[rp])]),
+        _ if op & 0b1100_0111 == 0b0000_0100 => ("INR", vec![Reg(dst)]),
+        _ if op & 0b1100_0111 == 0b0000_0101 => ("DCR", vec![Reg(dst)]),
+        _ if op & 0b1100_0111 == 0b0000_0110 => ("MVI", vec![Reg(dst), Imm8(d8)]),
+        0x02 => ("STAX", vec![Reg("B")]),
+        0x12 => ("STAX", vec![Reg("D")]),
+        0x0a => ("LDAX", vec![Reg("B")]),
+        0x1a => ("LDAX", vec![Reg("D")]),
+        0x22 => ("SHLD", vec![Addr(d16)]),
+        0x2a => ("LHLD", vec![Addr(d16)]),
+        0x32 => ("STA", vec![Addr(d16)]),
+        0x3a => ("LDA", vec![Addr(d16)]),
+        0x07 => ("RLC", vec![]),
+        0x0f => ("RRC", vec![]),
+        0x17 => ("RAL", vec![]),
+        0x1f => ("RAR", vec![]),
+        0x27 => ("DAA", vec![]),
+        0x2f => ("CMA", vec![]),
+        0x37 => ("STC", vec![]),
+        0x3f => ("CMC", vec![]),
+        0xc3 => ("JMP", vec![Addr(d16)]),
+        0xcd => ("CALL", vec![Addr(d16)]),
+        0xc9 => ("RET", vec![]),
+        _ if op & 0b1100_0111 == 0b1100_0010 => (JUMPS[ddd], vec![Addr(d16)]),
+        _ if op & 0b1100_0111 == 0b1100_0100 => (CALLS[ddd], vec![Addr(d16)]),
+        _ if op & 0b1100_0111 == 0b1100_0000 => (RETURNS[ddd], vec![]),
+        _ if op & 0b1100_0111 == 0b1100_0111 => ("RST", vec![Rst(ddd as u8)]),
+        _ if op & 0b1100_1111 == 0b1100_0001 => ("POP", vec![Reg(PAIRS_PSW[rp])]),
+        _ if op & 0b1100_1111 == 0b1100_0101 => ("PUSH", vec![Reg(PAIRS_PSW[rp])]),
+        0xd3 => ("OUT", vec![Imm8(d8)]),
+        0xdb => ("IN", vec![Imm8(d8)]),
+        0xe3 => ("XTHL", vec![]),
+        0xe9 => ("PCHL", vec![]),
+        0xeb => ("XCHG", vec![]),
+        0xf3 => ("DI", vec![]),
+        0xf9 => ("SPHL", vec![]),
+        0xfb => ("EI", vec![]),
+        _ => unreachable!("decode {:#04X}", op),
+    };
+
+    Instruction {
+        addr,
+        opcode: op,
+        len,
+        mnemonic,
+        operands,
+        bytes,
+    }
+}
+
+/// Linearly disassemble every instruction that starts inside `range`.
+pub fn disassemble_range(mem: &[u8], range: RangeInclusive<u16>) -> Vec<Instruction> {
+    let mut out = Vec::new();
+    let mut addr = *range.start() as usize;
+    while addr <= *range.end() as usize {
+        let instr = decode(mem, addr as u16);
+        addr += instr.len as usize;
+        out.push(instr);
+    }
+    out
+}
+
+/// One listing line: address, raw bytes and the instruction text.
+pub fn format_line(instr: &Instruction) -> String {
+    format_line_with(instr, &|_| None)
+}
+
+/// `format_line`, with address operands named by `name`.
+pub fn format_line_with(instr: &Instruction, name: &dyn Fn(u16) -> Option<String>) -> String {
+    let bytes: Vec<String> = instr.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "{:04X}  {:<9} {}",
+        instr.addr,
+        bytes.join(" "),
+        instr.format_with(name)
+    )
+}
+
+pub fn listing(instrs: &[Instruction]) -> String {
+    instrs
+        .iter()
+        .map(|instr| format_line(instr) + "\n")
+        .collect()
+}
+
+impl Intel8080 {
+    pub fn disassemble(&self, addr: u16) -> Instruction {
+        decode(self.memory(), addr)
+    }
+}
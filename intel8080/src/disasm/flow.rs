@@ -0,0 +1,293 @@
+// Recursive-descent disassembly.
+//
+// Starting from a set of entry points, follow every jump, branch and call
+// target the decoder reports. Bytes that are never reached are assumed to be
+// data and come out as DB lines, so tables embedded in a ROM don't get
+// disassembled as nonsense instructions.
+use super::{decode, hex16, hex8, Flow, Instruction};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+
+/// Where RST n (and therefore interrupt handlers) enter.
+pub const RST_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+const DB_PER_LINE: usize = 8;
+
+// Ordered by precedence: an address that is both jumped to and called gets
+// a subroutine label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Code,
+    Subroutine,
+}
+
+impl LabelKind {
+    fn prefix(&self) -> char {
+        match self {
+            LabelKind::Data => 'D',
+            LabelKind::Code => 'L',
+            LabelKind::Subroutine => 'S',
+        }
+    }
+}
+
+pub struct Analysis {
+    start: u16,
+    bytes: Vec<u8>,
+    // Set for every byte that belongs to a reached instruction.
+    covered: Vec<bool>,
+    instructions: BTreeMap<u16, Instruction>,
+    labels: BTreeMap<u16, String>,
+}
+
+/// Follow control flow through `range` from `entries`. Entry points outside
+/// the range are ignored, as are jumps that leave it. A reversed range
+/// gives an empty analysis.
+pub fn analyze(mem: &[u8], range: RangeInclusive<u16>, entries: &[u16]) -> Analysis {
+    let start = *range.start();
+    if range.is_empty() {
+        return Analysis {
+            start,
+            bytes: Vec::new(),
+            covered: Vec::new(),
+            instructions: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+    }
+    let end = *range.end() as usize;
+    let len = end + 1 - start as usize;
+    let bytes: Vec<u8> = (0..len)
+        .map(|i| mem.get(start as usize + i).copied().unwrap_or(0))
+        .collect();
+    let in_range = |addr: u16| addr >= start && addr as usize <= end;
+
+    let mut covered = vec![false; len];
+    let mut instructions = BTreeMap::new();
+    let mut kinds: BTreeMap<u16, LabelKind> = BTreeMap::new();
+    let mut pending: Vec<u16> = entries.iter().rev().copied().collect();
+    let mut seen = BTreeSet::new();
+
+    while let Some(addr) = pending.pop() {
+        if !in_range(addr) || !seen.insert(addr) {
+            continue;
+        }
+        let offset = (addr - start) as usize;
+        if covered[offset] {
+            // Jumps into the middle of an instruction are labelled but not
+            // decoded a second time.
+            continue;
+        }
+        let instr = decode(mem, addr);
+        let last = offset + instr.len as usize - 1;
+        if last >= len || covered[offset..=last].iter().any(|c| *c) {
+            continue;
+        }
+        covered[offset..=last].iter_mut().for_each(|c| *c = true);
+
+        let flow = instr.flow();
+        let mut mark = |target: u16, kind: LabelKind| {
+            if in_range(target) {
+                let entry = kinds.entry(target).or_insert(kind);
+                *entry = (*entry).max(kind);
+            }
+        };
+        match flow {
+            Flow::Jump(target) | Flow::Branch(target) => {
+                mark(target, LabelKind::Code);
+                pending.push(target);
+            }
+            Flow::Call(target) => {
+                mark(target, LabelKind::Subroutine);
+                pending.push(target);
+            }
+            _ => {
+                if let Some(target) = instr.address_operand() {
+                    mark(target, LabelKind::Data);
+                }
+            }
+        }
+        if flow.falls_through() {
+            pending.push(instr.next_addr());
+        }
+        instructions.insert(addr, instr);
+    }
+
+    let labels = kinds
+        .into_iter()
+        .map(|(addr, kind)| (addr, format!("{}{:04X}", kind.prefix(), addr)))
+        .collect();
+    Analysis {
+        start,
+        bytes,
+        covered,
+        instructions,
+        labels,
+    }
+}
+
+/// `analyze` with the usual entry points of a ROM: the start of the range
+/// plus every RST vector inside it.
+pub fn analyze_rom(mem: &[u8], range: RangeInclusive<u16>) -> Analysis {
+    let mut entries = vec![*range.start()];
+    entries.extend_from_slice(&RST_VECTORS);
+    analyze(mem, range, &entries)
+}
+
+impl Analysis {
+    pub fn is_code(&self, addr: u16) -> bool {
+        addr >= self.start
+            && self
+                .covered
+                .get((addr - self.start) as usize)
+                .copied()
+                .unwrap_or(false)
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.instructions.values()
+    }
+
+    pub fn labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Use the names in `symbols` for the labels they define exactly.
+    pub fn rename_labels(&mut self, symbols: &SymbolTable) {
+        for (addr, label) in self.labels.iter_mut() {
+            if let Some(name) = symbols.name_at(*addr) {
+                *label = name.to_string();
+            }
+        }
+    }
+
+    // Labels that can't be placed in front of a line because they point into
+    // the middle of an instruction.
+    fn floating_labels(&self) -> impl Iterator<Item = (&u16, &String)> {
+        self.labels
+            .iter()
+            .filter(|(addr, _)| self.is_code(**addr) && !self.instructions.contains_key(addr))
+    }
+
+    /// A listing that assembles back to the same bytes: ORG, EQUs for
+    /// labels inside instructions, then code and DB lines with the address
+    /// of each line in a comment.
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        for (addr, name) in self.floating_labels() {
+            out.push_str(&format!("{}\tEQU\t{}\n", name, hex16(*addr)));
+        }
+        out.push_str(&format!("\tORG\t{}\n", hex16(self.start)));
+
+        let name = |addr: u16| self.labels.get(&addr).cloned();
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let addr = self.start + offset as u16;
+            if let Some(label) = self.labels.get(&addr) {
+                out.push_str(&format!("{}:\n", label));
+            }
+            if let Some(instr) = self.instructions.get(&addr) {
+                let text = instr.format_with(name);
+                out.push_str(&format!("\t{:<24}; {:04X}\n", text, addr));
+                offset += instr.len as usize;
+                continue;
+            }
+            // A run of data, broken at labels, at code and every few bytes.
+            let mut values = vec![hex8(self.bytes[offset])];
+            offset += 1;
+            while offset < self.bytes.len()
+                && values.len() < DB_PER_LINE
+                && !self.covered[offset]
+                && !self.labels.contains_key(&(self.start + offset as u16))
+            {
+                values.push(hex8(self.bytes[offset]));
+                offset += 1;
+            }
+            let text = format!("DB {}", values.join(","));
+            out.push_str(&format!("\t{:<24}; {:04X}\n", text, addr));
+        }
+        out.push_str("\tEND\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn memory(source: &str) -> Vec<u8> {
+        let assembly = asm::assemble(source).unwrap();
+        let mut mem = vec![0; crate::MEMORY_SIZE];
+        for seg in &assembly.image.segments {
+            mem[seg.addr as usize..seg.end()].copy_from_slice(&seg.data);
+        }
+        mem
+    }
+
+    const PROGRAM: &str = "
+        ORG 100h
+        JMP START
+        JMP SUBR
+        JMP SUBR
+START:  LDA TABLE
+        CALL SUBR
+        MVI A,1
+        CPI 1
+        JNZ START
+        HLT
+SUBR:   MOV A,M
+        RET
+TABLE:  DB 0C3h,0,0,76h
+    ";
+
+    #[test]
+    fn code_and_data_are_separated() {
+        let mem = memory(PROGRAM);
+        let analysis = analyze(&mem, 0x100..=0x11C, &[0x100]);
+        // The jump table is code only as far as the first JMP leads.
+        assert!(analysis.is_code(0x100));
+        assert!(!analysis.is_code(0x103));
+        assert!(!analysis.is_code(0x106));
+        // Everything after RET is data even though it decodes as JMP.
+        assert!(analysis.is_code(0x117));
+        assert!(analysis.is_code(0x118));
+        assert!(!analysis.is_code(0x119));
+        assert_eq!(analysis.label(0x109), Some("L0109"));
+        assert_eq!(analysis.label(0x117), Some("S0117"));
+        assert_eq!(analysis.label(0x119), Some("D0119"));
+    }
+
+    #[test]
+    fn jump_table_entries_are_followed() {
+        let mem = memory(PROGRAM);
+        let analysis = analyze(&mem, 0x100..=0x11C, &[0x100, 0x103, 0x106]);
+        assert!(analysis.is_code(0x103));
+        assert!(analysis.is_code(0x106));
+        assert_eq!(analysis.instructions().count(), 11);
+    }
+
+    #[test]
+    fn listing_reassembles_to_the_same_bytes() {
+        let mem = memory(PROGRAM);
+        let analysis = analyze_rom(&mem, 0x100..=0x11C);
+        let assembly = asm::assemble(&analysis.listing()).unwrap();
+        let seg = &assembly.image.segments[0];
+        assert_eq!(seg.addr, 0x100);
+        assert_eq!(seg.data, &mem[0x100..=0x11C]);
+    }
+
+    #[test]
+    fn reversed_range_is_empty() {
+        let (start, end) = (0x10, 0x0F);
+        let analysis = analyze(&[0; 16], start..=end, &[start]);
+        assert_eq!(analysis.instructions().count(), 0);
+        assert!(!analysis.is_code(0x10));
+        assert_eq!(analysis.listing(), "\tORG\t0010h\n\tEND\n");
+    }
+}
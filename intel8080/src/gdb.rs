@@ -0,0 +1,506 @@
+// GDB remote serial protocol stub.
+//
+// Serves one debugger connection over TCP:
+//
+//   $ desktop gdb --port 1234 prog.hex
+//   (gdb) target remote localhost:1234
+//
+// Supported packets: ? g G p P m M s c Z0-Z4 z0-z4 k D, qSupported,
+// qAttached, QStartNoAckMode and qXfer:features:read for the register
+// layout below. Anything else gets the empty "unsupported" reply. A ^C from
+// the client interrupts a running `c`.
+//
+// Registers, in `g` packet order (16-bit ones little endian):
+//   0 a  1 b  2 c  3 d  4 e  5 h  6 l  7 flags  8 sp  9 pc
+use crate::debug::{Access, StopReason, WatchKind};
+use crate::Intel8080;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.intel8080.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="flags" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_BYTES: usize = 12;
+
+// `c` runs in slices of this many instructions, checking for ^C between.
+const CONTINUE_SLICE: usize = 10_000;
+
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn registers(cpu: &Intel8080) -> [u8; REGISTER_BYTES] {
+    let s = cpu.state();
+    let [sp_lo, sp_hi] = s.sp.to_le_bytes();
+    let [pc_lo, pc_hi] = s.pc.to_le_bytes();
+    [
+        s.a, s.b, s.c, s.d, s.e, s.h, s.l, s.flags, sp_lo, sp_hi, pc_lo, pc_hi,
+    ]
+}
+
+fn set_registers(cpu: &mut Intel8080, bytes: &[u8]) {
+    let mut s = cpu.state();
+    [s.a, s.b, s.c, s.d, s.e, s.h, s.l, s.flags] = bytes[..8].try_into().unwrap();
+    s.sp = u16::from_le_bytes([bytes[8], bytes[9]]);
+    s.pc = u16::from_le_bytes([bytes[10], bytes[11]]);
+    cpu.set_state(&s);
+}
+
+// Byte offset and size of register `n` within the `g` packet.
+fn register_slot(n: usize) -> Option<(usize, usize)> {
+    match n {
+        0..=7 => Some((n, 1)),
+        8 | 9 => Some((8 + (n - 8) * 2, 2)),
+        _ => None,
+    }
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint {
+            addr, access, kind, ..
+        } => {
+            let kind = match (kind, access) {
+                (WatchKind::ReadWrite, _) => "awatch",
+                (_, Access::Read) => "rwatch",
+                (_, Access::Write) => "watch",
+            };
+            format!("T{:02x}{}:{:04x};", SIGTRAP, kind, addr)
+        }
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+pub struct GdbStub<'a> {
+    cpu: &'a mut Intel8080,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    no_ack: bool,
+    last_reply: String,
+}
+
+/// Serve a single client on `stream` until it detaches, kills the target
+/// or disconnects.
+pub fn serve(cpu: &mut Intel8080, stream: TcpStream) -> io::Result<()> {
+    GdbStub::new(cpu, stream)?.run()
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(cpu: &'a mut Intel8080, stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            cpu,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            no_ack: false,
+            last_reply: String::new(),
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let Some(reply) = self.handle(&packet) else {
+                // k and D: acknowledge a detach, then stop serving.
+                if packet.starts_with('D') {
+                    self.send("OK")?;
+                }
+                return Ok(());
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.reader.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    // The next packet's payload, or None once the client disconnects.
+    // Acks are consumed here; a NAK resends the last reply.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(b'-') => {
+                    let reply = self.last_reply.clone();
+                    self.write_packet(&reply)?;
+                    continue;
+                }
+                Some(INTERRUPT) => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for b in &mut sum {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(c) => *b = c,
+                }
+            }
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum(&data)) {
+                    self.writer.write_all(b"-")?;
+                    continue;
+                }
+                self.writer.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())?;
+        self.writer.flush()
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.last_reply = data.to_string();
+        self.write_packet(data)
+    }
+
+    // The reply to `packet`, or None if the session should end.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => to_hex(&registers(self.cpu)),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == REGISTER_BYTES => {
+                    set_registers(self.cpu, &bytes);
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" | "c" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.cpu.set_pc(addr);
+                }
+                if command == "s" {
+                    self.step()
+                } else {
+                    self.resume()
+                }
+            }
+            "Z" | "z" => self.breakpoint(args, command == "Z"),
+            "H" => "OK".into(),
+            "k" | "D" => return None,
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".into()
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".into()
+        } else if packet == "qAttached" {
+            "1".into()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',') else {
+                return "E01".into();
+            };
+            let offset = usize::from_str_radix(offset, 16).unwrap_or(0);
+            let len = usize::from_str_radix(len, 16).unwrap_or(0);
+            let chunk = TARGET_XML.get(offset..).unwrap_or("");
+            if chunk.len() > len {
+                format!("m{}", &chunk[..len])
+            } else {
+                format!("l{}", chunk)
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_register(&mut self, args: &str) -> String {
+        let slot = usize::from_str_radix(args, 16).ok().and_then(register_slot);
+        match slot {
+            Some((offset, size)) => to_hex(&registers(self.cpu)[offset..offset + size]),
+            None => "E01".into(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".into();
+        };
+        let slot = usize::from_str_radix(n, 16).ok().and_then(register_slot);
+        match (slot, from_hex(value)) {
+            (Some((offset, size)), Some(bytes)) if bytes.len() == size => {
+                let mut regs = registers(self.cpu);
+                regs[offset..offset + size].copy_from_slice(&bytes);
+                set_registers(self.cpu, &regs);
+                "OK".into()
+            }
+            _ => "E01".into(),
+        }
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        let Some((addr, len)) = args.split_once(',') else {
+            return "E01".into();
+        };
+        match (parse_hex(addr), usize::from_str_radix(len, 16)) {
+            (Some(addr), Ok(len)) => {
+                let end = (addr as usize + len).min(self.cpu.memory().len());
+                to_hex(&self.cpu.memory()[addr as usize..end])
+            }
+            _ => "E01".into(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".into();
+        };
+        let Some((addr, _)) = range.split_once(',') else {
+            return "E01".into();
+        };
+        match (parse_hex(addr), from_hex(data)) {
+            (Some(addr), Some(bytes)) if self.cpu.load_at(addr, &bytes).is_ok() => "OK".into(),
+            _ => "E01".into(),
+        }
+    }
+
+    // Z/z type,addr,kind. Types: 0 and 1 break, 2 write, 3 read and 4
+    // access watchpoints covering `kind` bytes.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let [kind, addr, len] = fields[..] else {
+            return "E01".into();
+        };
+        let (Some(addr), Some(len)) = (parse_hex(addr), parse_hex(len)) else {
+            return "E01".into();
+        };
+        let range = addr..=addr.saturating_add(len.max(1) - 1);
+        let bps = self.cpu.breakpoints_mut();
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    bps.add(addr, None);
+                } else {
+                    bps.remove(addr);
+                }
+                return "OK".into();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::ReadWrite,
+            _ => return String::new(),
+        };
+        if insert {
+            bps.add_watch(range, watch, None);
+        } else {
+            bps.remove_watch(range, watch);
+        }
+        "OK".into()
+    }
+
+    // Run the CPU, reporting a panic (an unimplemented opcode) to the
+    // client as SIGILL rather than taking the server down. The panic hook is
+    // silenced meanwhile so the server's terminal isn't filled with
+    // backtraces.
+    fn execute(&mut self, limit: usize) -> Result<StopReason, String> {
+        let cpu = &mut *self.cpu;
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cpu.run(limit)));
+        panic::set_hook(hook);
+        result.map_err(|_| format!("S{:02x}", SIGILL))
+    }
+
+    fn step(&mut self) -> String {
+        match self.execute(1) {
+            Ok(reason) => stop_reply(reason),
+            Err(reply) => reply,
+        }
+    }
+
+    fn resume(&mut self) -> String {
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(reason) = self.cpu.breakpoint_hit() {
+                    return stop_reply(reason);
+                }
+            }
+            first = false;
+            match self.execute(CONTINUE_SLICE) {
+                Ok(StopReason::StepLimit) => {}
+                Ok(reason) => return stop_reply(reason),
+                Err(reply) => return reply,
+            }
+            if self.interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+
+    // Whether a ^C (or a disconnect) arrived while the target was running.
+    fn interrupted(&mut self) -> bool {
+        let stream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let result = match self.reader.fill_buf() {
+            Ok([]) => true,
+            Ok([INTERRUPT, ..]) => {
+                self.reader.consume(1);
+                true
+            }
+            _ => false,
+        };
+        self.reader.get_ref().set_nonblocking(false).ok();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Send each packet in turn and collect the replies.
+    fn client(addr: std::net::SocketAddr, packets: &[&str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut replies = Vec::new();
+        for packet in packets {
+            let data = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+            stream.write_all(data.as_bytes()).unwrap();
+            if *packet == "k" {
+                break;
+            }
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+', "packet {} not acknowledged", packet);
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            let mut reply = Vec::new();
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut sum = [0u8; 2];
+            stream.read_exact(&mut sum).unwrap();
+            let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap();
+            assert_eq!(sum, checksum(&reply));
+            stream.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(reply).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn scripted_session() {
+        let source = "LXI SP,2000h\nMVI A,5\nSTA 0300h\nHLT\n";
+        let assembly = asm::assemble(source).unwrap();
+        let mut cpu = Intel8080::new();
+        cpu.load_image(&assembly.image).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let packets = ["?", "g", "m0,3", "Z0,5,1", "c", "Z2,300,1", "c", "p0", "k"];
+        let client = thread::spawn(move || client(addr, &packets));
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut cpu, stream).unwrap();
+
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            [
+                "S05",
+                "000000000000000200000000",
+                "310020",
+                "OK",
+                "S05",
+                "OK",
+                "T05watch:0300;",
+                "05",
+            ]
+        );
+        assert_eq!(cpu.state().pc, 8);
+    }
+
+    #[test]
+    fn access_watchpoint_reports_awatch() {
+        let reason = StopReason::Watchpoint {
+            addr: 0x300,
+            access: Access::Write,
+            kind: WatchKind::ReadWrite,
+            value: 5,
+        };
+        assert_eq!(stop_reply(reason), "T05awatch:0300;");
+    }
+}
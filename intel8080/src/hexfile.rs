@@ -0,0 +1,388 @@
+// Text object formats produced by 8080 tooling: Intel HEX (as emitted by
+// ISIS and most cross assemblers) and Motorola S-records.
+use crate::{Image, Segment};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HexErrorKind {
+    MissingStartCode,
+    InvalidDigit,
+    BadLength,
+    ChecksumMismatch { expected: u8, found: u8 },
+    UnsupportedRecord(u8),
+    AddressOutOfRange(u32),
+    MissingEndRecord,
+}
+
+/// An error found while parsing a hex file, with the 1-based line number of
+/// the offending record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexError {
+    pub line: usize,
+    pub kind: HexErrorKind,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            HexErrorKind::MissingStartCode => write!(f, "record does not start with ':' or 'S'"),
+            HexErrorKind::InvalidDigit => write!(f, "invalid hex digit"),
+            HexErrorKind::BadLength => write!(f, "record length does not match its contents"),
+            HexErrorKind::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch: expected {:#04X}, found {:#04X}",
+                expected, found
+            ),
+            HexErrorKind::UnsupportedRecord(t) => write!(f, "unsupported record type {:02X}", t),
+            HexErrorKind::AddressOutOfRange(addr) => {
+                write!(f, "address {:#X} is outside 64 KiB", addr)
+            }
+            HexErrorKind::MissingEndRecord => write!(f, "missing end-of-file record"),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+fn decode_hex(line: usize, text: &str) -> Result<Vec<u8>, HexError> {
+    let err = |kind| HexError { line, kind };
+    if !text.len().is_multiple_of(2) {
+        return Err(err(HexErrorKind::BadLength));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(err(HexErrorKind::InvalidDigit))
+        })
+        .collect()
+}
+
+// Append data at addr, extending the last segment when the new bytes follow
+// on directly from it.
+fn push_data(segments: &mut Vec<Segment>, addr: u16, data: &[u8]) {
+    match segments.last_mut() {
+        Some(seg) if seg.end() == addr as usize => seg.data.extend_from_slice(data),
+        _ => segments.push(Segment::new(addr, data.to_vec())),
+    }
+}
+
+fn checked_addr(line: usize, addr: u32, len: usize) -> Result<u16, HexError> {
+    if addr > 0xFFFF || addr as usize + len > 0x1_0000 {
+        return Err(HexError {
+            line,
+            kind: HexErrorKind::AddressOutOfRange(addr),
+        });
+    }
+    Ok(addr as u16)
+}
+
+/// Parse Intel HEX. Supports data (00), end of file (01) and start segment
+/// address (03) records.
+pub fn parse_ihex(text: &str) -> Result<Image, HexError> {
+    let mut image = Image::default();
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let err = |kind| HexError { line, kind };
+        let record = raw.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let body = record
+            .strip_prefix(':')
+            .ok_or(err(HexErrorKind::MissingStartCode))?;
+        let bytes = decode_hex(line, body)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err(HexErrorKind::BadLength));
+        }
+        let (payload, checksum) = bytes.split_at(bytes.len() - 1);
+        let sum = payload.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        let expected = sum.wrapping_neg();
+        if expected != checksum[0] {
+            return Err(err(HexErrorKind::ChecksumMismatch {
+                expected,
+                found: checksum[0],
+            }));
+        }
+        let addr = u16::from_be_bytes([payload[1], payload[2]]);
+        let data = &payload[4..];
+        match payload[3] {
+            0x00 => {
+                let addr = checked_addr(line, addr as u32, data.len())?;
+                push_data(&mut image.segments, addr, data);
+            }
+            0x01 => return Ok(image),
+            0x03 => {
+                if data.len() != 4 {
+                    return Err(err(HexErrorKind::BadLength));
+                }
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                image.entry = Some(checked_addr(line, (cs << 4) + ip, 0)?);
+            }
+            t => return Err(err(HexErrorKind::UnsupportedRecord(t))),
+        }
+    }
+    Err(HexError {
+        line: text.lines().count(),
+        kind: HexErrorKind::MissingEndRecord,
+    })
+}
+
+/// Parse Motorola S-records. S0 headers and S5/S6 counts are accepted and
+/// ignored; S1/S2/S3 data and S7/S8/S9 start addresses must fall inside
+/// the 8080's 64 KiB address space.
+pub fn parse_srec(text: &str) -> Result<Image, HexError> {
+    let mut image = Image::default();
+    let mut terminated = false;
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        let err = |kind| HexError { line, kind };
+        let record = raw.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let mut chars = record.chars();
+        if chars.next() != Some('S') {
+            return Err(err(HexErrorKind::MissingStartCode));
+        }
+        let kind = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or(err(HexErrorKind::InvalidDigit))? as u8;
+        let bytes = decode_hex(line, &record[2..])?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err(HexErrorKind::BadLength));
+        }
+        let (payload, checksum) = bytes.split_at(bytes.len() - 1);
+        let sum = payload.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if !sum != checksum[0] {
+            return Err(err(HexErrorKind::ChecksumMismatch {
+                expected: !sum,
+                found: checksum[0],
+            }));
+        }
+        let addr_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            t => return Err(err(HexErrorKind::UnsupportedRecord(t))),
+        };
+        if payload.len() < 1 + addr_len {
+            return Err(err(HexErrorKind::BadLength));
+        }
+        let addr = payload[1..1 + addr_len]
+            .iter()
+            .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        let data = &payload[1 + addr_len..];
+        match kind {
+            1..=3 => {
+                let addr = checked_addr(line, addr, data.len())?;
+                push_data(&mut image.segments, addr, data);
+            }
+            7..=9 => {
+                image.entry = Some(checked_addr(line, addr, 0)?);
+                terminated = true;
+            }
+            _ => {}
+        }
+    }
+    if !terminated {
+        return Err(HexError {
+            line: text.lines().count(),
+            kind: HexErrorKind::MissingEndRecord,
+        });
+    }
+    Ok(image)
+}
+
+const IHEX_RECORD_LEN: usize = 16;
+
+fn ihex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut sum = (data.len() as u8)
+        .wrapping_add((addr >> 8) as u8)
+        .wrapping_add(addr as u8)
+        .wrapping_add(kind);
+    out.push_str(&format!(":{:02X}{:04X}{:02X}", data.len(), addr, kind));
+    for byte in data {
+        out.push_str(&format!("{:02X}", byte));
+        sum = sum.wrapping_add(*byte);
+    }
+    out.push_str(&format!("{:02X}\n", sum.wrapping_neg()));
+}
+
+/// Write segments as Intel HEX, 16 data bytes per record, followed by a
+/// start segment address record if the image has an entry point.
+pub fn write_ihex(image: &Image) -> String {
+    let mut out = String::new();
+    for seg in &image.segments {
+        for (i, chunk) in seg.data.chunks(IHEX_RECORD_LEN).enumerate() {
+            let addr = seg.addr.wrapping_add((i * IHEX_RECORD_LEN) as u16);
+            ihex_record(&mut out, addr, 0x00, chunk);
+        }
+    }
+    if let Some(entry) = image.entry {
+        let [hi, lo] = entry.to_be_bytes();
+        ihex_record(&mut out, 0, 0x03, &[0, 0, hi, lo]);
+    }
+    ihex_record(&mut out, 0, 0x01, &[]);
+    out
+}
+
+/// Format bytes as a classic hexdump: address, 16 bytes, ASCII column.
+/// `addr` is the address of the first byte.
+pub fn hexdump(addr: u16, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let line_addr = addr.wrapping_add((i * 16) as u16);
+        out.push_str(&format!("{:04X}: ", line_addr));
+        for col in 0..16 {
+            match chunk.get(col) {
+                Some(byte) => out.push_str(&format!("{:02X} ", byte)),
+                None => out.push_str("   "),
+            }
+            if col == 7 {
+                out.push(' ');
+            }
+        }
+        out.push(' ');
+        for byte in chunk {
+            let c = *byte as char;
+            out.push(if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ihex(addr: u16, kind: u8, data: &[u8]) -> String {
+        let mut out = String::new();
+        ihex_record(&mut out, addr, kind, data);
+        out
+    }
+
+    fn srec(kind: u8, addr: &[u8], data: &[u8]) -> String {
+        let count = (addr.len() + data.len() + 1) as u8;
+        let bytes: Vec<u8> = [&[count][..], addr, data].concat();
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("S{}{}{:02X}\n", kind, hex, !sum)
+    }
+
+    fn kind(result: Result<Image, HexError>) -> (usize, HexErrorKind) {
+        let err = result.unwrap_err();
+        (err.line, err.kind)
+    }
+
+    #[test]
+    fn ihex_round_trip() {
+        let image = Image {
+            segments: vec![
+                Segment::new(0x100, (0..40).collect()),
+                Segment::new(0x2000, vec![0xAA]),
+            ],
+            entry: Some(0x100),
+        };
+        assert_eq!(parse_ihex(&write_ihex(&image)).unwrap(), image);
+    }
+
+    #[test]
+    fn ihex_errors() {
+        let eof = ihex(0, 0x01, &[]);
+        let bad_sum = ":0101000000FF\n".to_string() + &eof;
+        assert_eq!(
+            kind(parse_ihex(&bad_sum)),
+            (
+                1,
+                HexErrorKind::ChecksumMismatch {
+                    expected: 0xFE,
+                    found: 0xFF
+                }
+            )
+        );
+        // Claims two data bytes but carries one.
+        assert_eq!(
+            kind(parse_ihex(":0201000000FD\n")),
+            (1, HexErrorKind::BadLength)
+        );
+        let unknown = ihex(0, 0x04, &[0, 1]) + &eof;
+        assert_eq!(
+            kind(parse_ihex(&unknown)),
+            (1, HexErrorKind::UnsupportedRecord(0x04))
+        );
+        let no_eof = ihex(0x100, 0x00, &[1, 2]) + &ihex(0x102, 0x00, &[3]);
+        assert_eq!(
+            kind(parse_ihex(&no_eof)),
+            (2, HexErrorKind::MissingEndRecord)
+        );
+        assert_eq!(
+            kind(parse_ihex("0000000001FF\n")),
+            (1, HexErrorKind::MissingStartCode)
+        );
+    }
+
+    #[test]
+    fn ihex_start_address() {
+        // CS=0010h, IP=0005h: 0105h.
+        let text = ihex(0x100, 0x00, &[0x76]) + &ihex(0, 0x03, &[0, 0x10, 0, 5]) + &ihex(0, 1, &[]);
+        let image = parse_ihex(&text).unwrap();
+        assert_eq!(image.entry, Some(0x105));
+        assert_eq!(image.segments, vec![Segment::new(0x100, vec![0x76])]);
+    }
+
+    #[test]
+    fn srec_start_addresses() {
+        let data = srec(1, &[0x01, 0x00], &[0x3E, 0x05]) + &srec(1, &[0x01, 0x02], &[0x76]);
+        for (kind, addr) in [
+            (9, &[0x01, 0x00][..]),
+            (8, &[0x00, 0x01, 0x00][..]),
+            (7, &[0x00, 0x00, 0x01, 0x00][..]),
+        ] {
+            let text = srec(0, &[0, 0], b"HDR") + &data + &srec(kind, addr, &[]);
+            let image = parse_srec(&text).unwrap();
+            assert_eq!(image.entry, Some(0x100), "S{}", kind);
+            assert_eq!(
+                image.segments,
+                vec![Segment::new(0x100, vec![0x3E, 0x05, 0x76])]
+            );
+        }
+    }
+
+    #[test]
+    fn srec_errors() {
+        let end = srec(9, &[0, 0], &[]);
+        let mut bad_sum = srec(1, &[0x01, 0x00], &[0x76]);
+        bad_sum.replace_range(bad_sum.len() - 3..bad_sum.len() - 1, "00");
+        assert!(matches!(
+            kind(parse_srec(&(bad_sum + &end))),
+            (1, HexErrorKind::ChecksumMismatch { found: 0, .. })
+        ));
+        // Byte count one higher than the record.
+        assert_eq!(
+            kind(parse_srec("S1050100767F\n")),
+            (1, HexErrorKind::BadLength)
+        );
+        assert_eq!(
+            kind(parse_srec(&(srec(4, &[0, 0], &[]) + &end))),
+            (1, HexErrorKind::UnsupportedRecord(4))
+        );
+        assert_eq!(
+            kind(parse_srec(&srec(1, &[0x01, 0x00], &[0x76]))),
+            (1, HexErrorKind::MissingEndRecord)
+        );
+        assert_eq!(
+            kind(parse_srec(&(srec(2, &[0x01, 0x00, 0x00], &[0x76]) + &end))),
+            (1, HexErrorKind::AddressOutOfRange(0x10000))
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Instruction history for reverse execution.
+//
+// While recording, every executed instruction leaves an entry holding the
+// register state and cycle count from before it ran and the old value of
+// every byte it wrote. Undoing an entry puts them all back. The buffer is
+// bounded: once full, the oldest entry is dropped for each new one.
+//
+// Only writes made by instructions are recorded. Memory changed from
+// outside (load_at, memory_mut) is not undone by stepping back. Calls and
+// returns are remembered too, so a tracked call stack steps back with the
+// rest of the state.
+use crate::callstack::Frame;
+use crate::{CpuState, Intel8080};
+use std::collections::VecDeque;
+
+/// A byte written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The CPU state just before the instruction executed.
+    pub state: CpuState,
+    /// The cycle count just before the instruction executed.
+    pub cycles: u64,
+    pub writes: Vec<MemoryWrite>,
+    // Whether the instruction opened a call stack frame, and the frames
+    // it closed.
+    entered: bool,
+    left: Vec<Frame>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(4096)),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Entries from oldest to most recent.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn begin(&mut self, state: CpuState, cycles: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            state,
+            cycles,
+            writes: Vec::new(),
+            entered: false,
+            left: Vec::new(),
+        });
+    }
+
+    pub(crate) fn record_write(&mut self, addr: u16, old: u8, new: u8) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.writes.push(MemoryWrite { addr, old, new });
+        }
+    }
+
+    pub(crate) fn record_calls(&mut self, entered: bool, left: Vec<Frame>) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.entered = entered;
+            entry.left = left;
+        }
+    }
+}
+
+impl Intel8080 {
+    /// Start recording up to `capacity` instructions, discarding any
+    /// history recorded so far.
+    pub fn record_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undo the most recent instruction. Returns false when there is no
+    /// history left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.history.as_mut().and_then(|h| h.entries.pop_back()) else {
+            return false;
+        };
+        for write in entry.writes.iter().rev() {
+            self.memory[write.addr as usize] = write.old;
+        }
+        self.set_state(&entry.state);
+        self.cycles = entry.cycles;
+        if let Some(calls) = &mut self.calls {
+            calls.undo(entry.entered, &entry.left);
+        }
+        true
+    }
+
+    /// Undo up to `count` instructions and return how many were undone.
+    pub fn rewind(&mut self, count: usize) -> usize {
+        (0..count).take_while(|_| self.step_back()).count()
+    }
+
+    /// Rewind to just before the most recent recorded instruction that
+    /// wrote to `addr`, leaving PC on that instruction. Returns how many
+    /// instructions were undone, or None (changing nothing) if no recorded
+    /// instruction wrote there.
+    pub fn rewind_to_write(&mut self, addr: u16) -> Option<usize> {
+        let history = self.history.as_ref()?;
+        let back = history
+            .iter()
+            .rev()
+            .position(|entry| entry.writes.iter().any(|w| w.addr == addr))?;
+        Some(self.rewind(back + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    // MVI at 0000, STA 2400h at 0002, MVI at 0005, STA 2401h at 0007,
+    // HLT at 000A.
+    const PROGRAM: &str = "MVI A,1; STA 2400h; MVI A,2; STA 2401h; HLT";
+
+    fn recorded(capacity: usize) -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.record_history(capacity);
+        asm::run_on(cpu, PROGRAM)
+    }
+
+    #[test]
+    fn step_back_undoes_one_instruction() {
+        let mut cpu = recorded(100);
+        assert_eq!(cpu.history().unwrap().len(), 5);
+        let cycles = cpu.cycles();
+
+        assert!(cpu.step_back());
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.state().pc, 0x0A);
+        assert_eq!(cpu.cycles(), cycles - 7);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.state().pc, 0x07);
+        assert_eq!(cpu.memory()[0x2401], 0);
+        assert_eq!(cpu.memory()[0x2400], 1);
+        assert_eq!(cpu.state().a, 2);
+    }
+
+    #[test]
+    fn rewind_stops_when_history_runs_out() {
+        let mut cpu = recorded(100);
+        assert_eq!(cpu.rewind(3), 3);
+        assert_eq!(cpu.state().pc, 0x05);
+        assert_eq!(cpu.rewind(10), 2);
+        assert_eq!(cpu.state(), Intel8080::new().state());
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.memory()[0x2400], 0);
+        assert!(!cpu.step_back());
+    }
+
+    #[test]
+    fn rewind_to_write_stops_on_the_writer() {
+        let mut cpu = recorded(100);
+        assert_eq!(cpu.rewind_to_write(0x3000), None);
+        assert_eq!(cpu.history().unwrap().len(), 5);
+
+        assert_eq!(cpu.rewind_to_write(0x2400), Some(4));
+        assert_eq!(cpu.state().pc, 0x02);
+        assert_eq!(cpu.memory()[0x2400], 0);
+        assert_eq!(cpu.state().a, 1);
+    }
+
+    #[test]
+    fn full_history_drops_the_oldest_entry() {
+        let mut cpu = recorded(2);
+        let history = cpu.history().unwrap();
+        assert_eq!(history.len(), 2);
+        let pcs: Vec<u16> = history.iter().map(|e| e.state.pc).collect();
+        assert_eq!(pcs, [0x07, 0x0A]);
+        let writes = &history.iter().next().unwrap().writes;
+        assert_eq!(
+            writes,
+            &[MemoryWrite {
+                addr: 0x2401,
+                old: 0,
+                new: 2
+            }]
+        );
+
+        assert_eq!(cpu.rewind(5), 2);
+        assert_eq!(cpu.state().pc, 0x07);
+        // Writes older than the history stay.
+        assert_eq!(cpu.memory()[0x2400], 1);
+    }
+}
@@ -1,3 +1,52 @@
+/// Assemble a short program, run it on a fresh `Intel8080` until HLT and
+/// evaluate to the final `CpuState`, for writing instruction tests in
+/// assembly. Statements can be separated with `;`:
+///
+/// ```
+/// use intel8080::run_asm;
+///
+/// let state = run_asm!("MVI A,5; ADI 3; HLT");
+/// assert_eq!(state.a, 8);
+/// ```
+///
+/// Use `asm::run` directly to get at memory as well.
+#[macro_export]
+macro_rules! run_asm {
+    ($source:expr) => {
+        $crate::asm::run($source).state()
+    };
+}
+
+pub mod asm;
+pub mod callstack;
+pub mod coverage;
+pub mod debug;
+pub mod disasm;
+pub mod gdb;
+pub mod hexfile;
+pub mod history;
+pub mod memory;
+pub mod observer;
+pub mod profile;
+pub mod sanitizer;
+pub mod savestate;
+pub mod smc;
+pub mod state;
+pub mod symbols;
+pub mod trace;
+
+use callstack::{CallStack, Frame, FrameKind};
+use coverage::Coverage;
+use debug::{Access, Breakpoints, StopReason};
+use history::History;
+use observer::Observers;
+use profile::Profiler;
+use sanitizer::Sanitizer;
+use smc::SmcDetector;
+use std::fmt;
+use std::ops::RangeInclusive;
+use trace::Tracer;
+
 const MEMORY_SIZE: usize = 65_536;
 // I decided to use an array of 8 registers so that I can get the specified
 // register directly from the opcode byte.
@@ -12,6 +61,50 @@ const REG_L: usize = 0x05; // 0b0000_0101
 const REG_A: usize = 0x07; // 0b0000_0111
 const M_REF: u8 = 0x06; // 0b0000_0110
 
+// Clock cycles per opcode, from the 8080 datasheet. Conditional calls and
+// returns are listed with their not-taken time; taking them costs
+// COND_TAKEN_EXTRA more.
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x00
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x10
+    4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 0x20
+    4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 0x30
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 0x40
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 0x50
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 0x60
+    7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 0x70
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x80
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x90
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0xA0
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0xB0
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // 0xC0
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // 0xD0
+    5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // 0xE0
+    5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // 0xF0
+];
+const COND_TAKEN_EXTRA: u64 = 6;
+
+// How an executed instruction changed subroutine nesting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StackEffect {
+    None,
+    Call,
+    Return,
+}
+
+// Classify `op`, executed at `pc` and leaving PC at `next`. Conditional
+// calls and returns only count when taken. RST is a call.
+fn stack_effect(op: u8, pc: u16, next: u16) -> StackEffect {
+    match (op, op & 0b1100_0111) {
+        (0xcd, _) | (_, 0b1100_0111) => StackEffect::Call,
+        (0xc9, _) => StackEffect::Return,
+        (_, 0b1100_0100) if next != pc.wrapping_add(3) => StackEffect::Call,
+        (_, 0b1100_0000) if next != pc.wrapping_add(1) => StackEffect::Return,
+        _ => StackEffect::None,
+    }
+}
+
 struct ConditionCodes {
     s: u8,  // Set if the result of an operation is zero.
     z: u8,  // Set if the MS bit of the result is 1, indicating a negative number.
@@ -19,13 +112,122 @@ struct ConditionCodes {
     cy: u8, // Carry flag
     ac: u8, // Auxiliary carry flag, (space invaders doesn't use it)
 }
+
+/// A snapshot of the programmer-visible CPU state: the seven working
+/// registers, SP, PC, the condition bits packed in PSW layout and the
+/// INTE flip-flop.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub flags: u8,
+    pub interrupts_enable: bool,
+    pub halted: bool,
+}
+
+impl CpuState {
+    /// The condition bits as letters, upper case when set: "SZAPC", with
+    /// a '-' for each clear bit.
+    pub fn flag_letters(&self) -> String {
+        state::Flags(self.flags).to_string()
+    }
+}
+
+/// A block of bytes destined for a fixed address in memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn new(addr: u16, data: Vec<u8>) -> Self {
+        Self { addr, data }
+    }
+
+    // One past the last address covered, as usize so a segment ending at
+    // 0xFFFF doesn't wrap.
+    pub fn end(&self) -> usize {
+        self.addr as usize + self.data.len()
+    }
+}
+
+/// A program made of one or more segments, plus the entry point if the
+/// file it came from specified one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    OutOfBounds {
+        addr: u16,
+        len: usize,
+    },
+    Overlap {
+        first: u16,
+        second: u16,
+    },
+    /// A source range that ends before it starts.
+    ReversedRange {
+        start: u16,
+        end: u16,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::OutOfBounds { addr, len } => write!(
+                f,
+                "{} bytes at {:#06X} do not fit in 64 KiB of memory",
+                len, addr
+            ),
+            LoadError::Overlap { first, second } => write!(
+                f,
+                "segment at {:#06X} overlaps segment at {:#06X}",
+                second, first
+            ),
+            LoadError::ReversedRange { start, end } => {
+                write!(f, "range {:#06X}-{:#06X} ends before it starts", start, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 pub struct Intel8080 {
     registers: [u8; REGISTER_NUM],
-    memory: [u8; MEMORY_SIZE],
+    // Boxed so that an Intel8080 is cheap to move around and doesn't put
+    // 64 KiB on the stack of whoever creates it.
+    memory: Box<[u8; MEMORY_SIZE]>,
     pc: u16,
     sp: u16,
     cc: ConditionCodes,
     interrupts_enable: bool,
+    halted: bool,
+    breakpoints: Breakpoints,
+    // Set by a watchpoint hit during the current instruction.
+    stop: Option<StopReason>,
+    history: Option<History>,
+    cycles: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    calls: Option<CallStack>,
+    sanitizer: Option<Sanitizer>,
+    smc: Option<SmcDetector>,
+    observers: Observers,
 }
 
 impl ConditionCodes {
@@ -38,6 +240,22 @@ impl ConditionCodes {
             ac: 0,
         }
     }
+
+    // Pack the condition bits the way PUSH PSW stores them on the stack:
+    // S Z 0 AC 0 P 1 CY
+    fn to_psw(&self) -> u8 {
+        0b0000_0010 | self.cy | (self.p << 2) | (self.ac << 4) | (self.z << 6) | (self.s << 7)
+    }
+
+    fn from_psw(psw: u8) -> Self {
+        Self {
+            s: (psw & 0b1000_0000) >> 7,
+            z: (psw & 0b0100_0000) >> 6,
+            p: (psw & 0b0000_0100) >> 2,
+            cy: psw & 0b0000_0001,
+            ac: (psw & 0b0001_0000) >> 4,
+        }
+    }
 }
 
 // Utility function to calculate parity
@@ -51,72 +269,136 @@ fn is_parity_even(byte: u8) -> bool {
     (count % 2) == 0
 }
 
+impl Default for Intel8080 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Intel8080 {
     pub fn new() -> Self {
         Self {
             registers: [0; REGISTER_NUM],
-            memory: [0; MEMORY_SIZE],
+            memory: vec![0; MEMORY_SIZE]
+                .into_boxed_slice()
+                .try_into()
+                .expect("memory size"),
             pc: 0,
             sp: 0,
             cc: ConditionCodes::new(),
             interrupts_enable: false,
+            halted: false,
+            breakpoints: Breakpoints::new(),
+            stop: None,
+            history: None,
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            calls: None,
+            sanitizer: None,
+            smc: None,
+            observers: Observers::default(),
         }
     }
 
+    /// Return the CPU to its power-on state, keeping the existing memory
+    /// allocation and any breakpoints. Attached tools stay attached but
+    /// forget what they recorded; a tracer keeps writing to its output.
+    pub fn reset(&mut self) {
+        self.registers = [0; REGISTER_NUM];
+        self.memory.fill(0);
+        self.pc = 0;
+        self.sp = 0;
+        self.cc = ConditionCodes::new();
+        self.interrupts_enable = false;
+        self.halted = false;
+        self.stop = None;
+        self.cycles = 0;
+        self.clear_tools();
+    }
+
+    // Make attached tools forget what they recorded, after the machine
+    // state has been replaced.
+    pub(crate) fn clear_tools(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        if let Some(calls) = &mut self.calls {
+            calls.clear();
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.clear();
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.clear();
+        }
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.clear();
+        }
+        if let Some(smc) = &mut self.smc {
+            smc.clear();
+        }
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.registers[REG_A],
+            b: self.registers[REG_B],
+            c: self.registers[REG_C],
+            d: self.registers[REG_D],
+            e: self.registers[REG_E],
+            h: self.registers[REG_H],
+            l: self.registers[REG_L],
+            sp: self.sp,
+            pc: self.pc,
+            flags: self.cc.to_psw(),
+            interrupts_enable: self.interrupts_enable,
+            halted: self.halted,
+        }
+    }
+
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.registers[REG_A] = state.a;
+        self.registers[REG_B] = state.b;
+        self.registers[REG_C] = state.c;
+        self.registers[REG_D] = state.d;
+        self.registers[REG_E] = state.e;
+        self.registers[REG_H] = state.h;
+        self.registers[REG_L] = state.l;
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.cc = ConditionCodes::from_psw(state.flags);
+        self.interrupts_enable = state.interrupts_enable;
+        self.halted = state.halted;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory[..]
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[..]
+    }
+
+    /// The bytes in `range`; empty if the range is reversed.
+    pub fn memory_range(&self, range: RangeInclusive<u16>) -> &[u8] {
+        if range.is_empty() {
+            return &[];
+        }
+        &self.memory[*range.start() as usize..=*range.end() as usize]
+    }
+
+    /// Copy an address range out as a segment, ready to be written as
+    /// Intel HEX or reloaded with `load_segments`. A reversed range gives
+    /// an empty segment.
+    pub fn export(&self, range: RangeInclusive<u16>) -> Segment {
+        Segment::new(*range.start(), self.memory_range(range).to_vec())
+    }
+
+    #[deprecated(note = "use the Display impl or format_state")]
     pub fn print_state(&self) {
-        println!("opcode: {:#04X}", self.memory[self.pc as usize]);
-        println!("        CPU Misc. Field State");
-        println!("-------------------------------------------");
-        println!("FIELD |DEC\t|HEX\t|BIN               |");
-        println!("-------------------------------------------|");
-        println!("PC    |{}\t|{:#06X}\t|{:#018b}|", self.pc, self.pc, self.pc);
-        println!("SP    |{}\t|{:#06X}\t|{:#018b}|", self.sp, self.sp, self.sp);
-        println!("-------------------------------------------|");
-        println!("                                           |");
-        println!("        CPU Register State                 |");
-        println!("-------------------------------------------|");
-        println!("REGISTER |DEC\t|HEX\t|BIN               |");
-        println!("-------------------------------------------|");
-        println!(
-            "A        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_A], self.registers[REG_A], self.registers[REG_A]
-        );
-        println!(
-            "B        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_B], self.registers[REG_B], self.registers[REG_B]
-        );
-        println!(
-            "C        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_C], self.registers[REG_C], self.registers[REG_C]
-        );
-        println!(
-            "D        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_D], self.registers[REG_D], self.registers[REG_D]
-        );
-        println!(
-            "E        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_E], self.registers[REG_E], self.registers[REG_E]
-        );
-        println!(
-            "H        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_H], self.registers[REG_H], self.registers[REG_H]
-        );
-        println!(
-            "L        |{}\t|{:#04X}\t|{:#010b}        |",
-            self.registers[REG_L], self.registers[REG_L], self.registers[REG_L]
-        );
-        println!("-------------------------------------------|");
-        println!("                                           |");
-        println!("        CPU FLAG State                     |");
-        println!("-------------------------------------------|");
-        println!("        FLAG         |       VALUE         |");
-        println!("-------------------------------------------|");
-        println!("  CARRY              | {:#04X}\t           |", self.cc.cy);
-        println!("  PARITY             | {:#04X}\t           |", self.cc.p);
-        println!("  AUX-CARRY          | {:#04X}\t           |", self.cc.ac);
-        println!("  ZERO               | {:#04X}\t           |", self.cc.z);
-        println!("  SIGN               | {:#04X}\t           |", self.cc.s);
-        println!("-------------------------------------------\n\n");
+        println!("{:#}\n\n", self);
     }
 
     // TODO: This is just for testing purposes
@@ -124,21 +406,252 @@ impl Intel8080 {
         self.pc
     }
 
-    pub fn load(&mut self, data: &[u8]) {
-        let end = data.len();
-        self.memory[..end].copy_from_slice(data);
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+    }
+
+    pub fn load(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        self.load_at(0, data)
+    }
+
+    pub fn load_at(&mut self, addr: u16, data: &[u8]) -> Result<(), LoadError> {
+        let start = addr as usize;
+        if start + data.len() > MEMORY_SIZE {
+            return Err(LoadError::OutOfBounds {
+                addr,
+                len: data.len(),
+            });
+        }
+        self.memory[start..start + data.len()].copy_from_slice(data);
+        if let Some(sanitizer) = &mut self.sanitizer {
+            if !data.is_empty() {
+                sanitizer.mark_initialized(addr..=(start + data.len() - 1) as u16);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load several segments, e.g. a ROM set split across chips. Nothing is
+    /// written unless every segment fits and no two segments overlap.
+    pub fn load_segments(&mut self, segments: &[Segment]) -> Result<(), LoadError> {
+        for (i, seg) in segments.iter().enumerate() {
+            if seg.end() > MEMORY_SIZE {
+                return Err(LoadError::OutOfBounds {
+                    addr: seg.addr,
+                    len: seg.data.len(),
+                });
+            }
+            for other in &segments[..i] {
+                if (seg.addr as usize) < other.end() && (other.addr as usize) < seg.end() {
+                    return Err(LoadError::Overlap {
+                        first: other.addr,
+                        second: seg.addr,
+                    });
+                }
+            }
+        }
+        for seg in segments {
+            self.load_at(seg.addr, &seg.data)?;
+        }
+        Ok(())
+    }
+
+    /// Load every segment of the image and jump to its entry point, if it
+    /// has one.
+    pub fn load_image(&mut self, image: &Image) -> Result<(), LoadError> {
+        self.load_segments(&image.segments)?;
+        if let Some(entry) = image.entry {
+            self.pc = entry;
+        }
+        Ok(())
     }
 
     fn fetch(&mut self) -> u8 {
-        let op = self.memory[self.pc as usize];
-        op
+        self.memory[self.pc as usize]
+    }
+
+    // Data reads and writes made by instructions go through these two so
+    // that watchpoints see them. Opcode and operand fetches don't.
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_read(addr);
+        }
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.check_read(addr);
+        }
+        if !self.observers.is_empty() {
+            self.observers.each(|o| o.read(addr, value));
+        }
+        if !self.breakpoints.watchpoints().is_empty() {
+            self.check_watchpoints(addr, Access::Read, value);
+        }
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if let Some(history) = &mut self.history {
+            history.record_write(addr, self.memory[addr as usize], value);
+        }
+        if let Some(smc) = &mut self.smc {
+            smc.check_write(addr, self.memory[addr as usize], value);
+        }
+        if !self.observers.is_empty() {
+            let old = self.memory[addr as usize];
+            self.observers.each(|o| o.write(addr, old, value));
+        }
+        self.memory[addr as usize] = value;
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_write(addr);
+        }
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.check_write(addr, value);
+        }
+        if !self.breakpoints.watchpoints().is_empty() {
+            self.check_watchpoints(addr, Access::Write, value);
+        }
+    }
+
+    /// Clock cycles spent since power-on or the last reset.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
     }
 
     pub fn tick(&mut self) {
+        // A halted CPU does nothing until it is interrupted or reset.
+        if self.halted {
+            return;
+        }
+        if self.history.is_some() {
+            let state = self.state();
+            if let Some(history) = &mut self.history {
+                history.begin(state, self.cycles);
+            }
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self);
+            self.tracer = Some(tracer);
+        }
         // Fetch
         let op: u8 = self.fetch();
+        if !self.observers.is_empty() {
+            let state = self.state();
+            self.observers.each(|o| o.fetch(&state, op));
+        }
+        let pc = self.pc;
+        let cycles = self.cycles;
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.begin(pc);
+        }
+        if let Some(smc) = &mut self.smc {
+            smc.begin(pc, op);
+        }
         // Decode && Execute
         self.execute(op);
+        // Counted only once it has run, so an opcode that fails isn't
+        // reported as covered.
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_instruction(pc, op);
+        }
+        self.cycles += CYCLES[op as usize] as u64;
+        // Rcc is 11ccc000 and Ccc 11ccc100; either one skipped falls
+        // through to the next instruction.
+        let fall_through = match op & 0b1100_0111 {
+            0b1100_0000 => Some(pc.wrapping_add(1)),
+            0b1100_0100 => Some(pc.wrapping_add(3)),
+            _ => None,
+        };
+        if fall_through.is_some_and(|next| next != self.pc) {
+            self.cycles += COND_TAKEN_EXTRA;
+        }
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.check_sp(self.sp);
+        }
+        if self.profiler.is_none() && self.calls.is_none() {
+            return;
+        }
+        let effect = stack_effect(op, pc, self.pc);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, op, self.cycles - cycles, effect, self.pc);
+        }
+        if let Some(calls) = &mut self.calls {
+            let (entered, left) = match effect {
+                StackEffect::Call => (true, Vec::new()),
+                StackEffect::Return => (false, calls.leave(pc, self.pc, self.sp)),
+                StackEffect::None => return,
+            };
+            if entered {
+                calls.enter(Frame {
+                    kind: if op & 0b1100_0111 == 0b1100_0111 {
+                        FrameKind::Restart
+                    } else {
+                        FrameKind::Call
+                    },
+                    site: pc,
+                    target: self.pc,
+                    return_addr: pc.wrapping_add(disasm::instruction_length(op) as u16),
+                    sp: self.sp,
+                });
+            }
+            if let Some(history) = &mut self.history {
+                history.record_calls(entered, left);
+            }
+        }
+    }
+
+    /// Raise an interrupt that executes RST `vector` (0-7). Ignored, and
+    /// false returned, while interrupts are disabled. Otherwise interrupts
+    /// are disabled, a halted CPU wakes up, and PC is pushed as the return
+    /// address.
+    pub fn interrupt(&mut self, vector: u8) -> bool {
+        if !self.interrupts_enable {
+            return false;
+        }
+        if self.history.is_some() {
+            let state = self.state();
+            if let Some(history) = &mut self.history {
+                history.begin(state, self.cycles);
+            }
+        }
+        self.interrupts_enable = false;
+        self.halted = false;
+        let ret_addr = self.pc;
+        if !self.observers.is_empty() {
+            self.observers
+                .each(|o| o.interrupt(vector & 0b111, ret_addr));
+        }
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.begin_interrupt(ret_addr);
+        }
+        // Same byte order as CALL, so RET finds it.
+        self.write(self.sp.wrapping_sub(1), (ret_addr & 0x00FF) as u8);
+        self.write(self.sp.wrapping_sub(2), (ret_addr >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(2);
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.check_sp(self.sp);
+        }
+        self.pc = ((vector & 0b111) as u16) << 3;
+        self.cycles += CYCLES[0xc7] as u64;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(self.pc, CYCLES[0xc7] as u64);
+        }
+        if let Some(calls) = &mut self.calls {
+            calls.enter(Frame {
+                kind: FrameKind::Interrupt,
+                site: ret_addr,
+                target: self.pc,
+                return_addr: ret_addr,
+                sp: self.sp,
+            });
+            if let Some(history) = &mut self.history {
+                history.record_calls(true, Vec::new());
+            }
+        }
+        true
     }
 
     // Update Zero, Sign, and Parity flags based on the contents of a register
@@ -281,7 +794,7 @@ impl Intel8080 {
             0x73 => unimplemented!("Error: Unimplemented opcode."),
             0x74 => unimplemented!("Error: Unimplemented opcode."),
             0x75 => unimplemented!("Error: Unimplemented opcode."),
-            0x76 => unimplemented!("Error: Unimplemented opcode."),
+            0x76 => self.hlt(),
             0x77 => self.mov(),
             0x78 => unimplemented!("Error: Unimplemented opcode."),
             0x79 => unimplemented!("Error: Unimplemented opcode."),
@@ -457,7 +970,7 @@ impl Intel8080 {
             M_REF => {
                 let offset: u16 =
                     ((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16);
-                self.registers[REG_A] &= self.memory[offset as usize];
+                self.registers[REG_A] &= self.read(offset);
             }
             _ => {
                 self.registers[REG_A] &= self.registers[reg as usize];
@@ -539,13 +1052,13 @@ impl Intel8080 {
         let dst = (isntruction & 0b0011_1000) >> 3;
         let src = isntruction & 0b0000_0111;
         if dst == M_REF {
-            let offset: usize =
-                (((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16)) as usize;
-            self.memory[offset] = self.registers[src as usize];
+            let offset: u16 =
+                ((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16);
+            self.write(offset, self.registers[src as usize]);
         } else if src == M_REF {
-            let offset: usize =
-                (((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16)) as usize;
-            self.registers[dst as usize] = self.memory[offset];
+            let offset: u16 =
+                ((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16);
+            self.registers[dst as usize] = self.read(offset);
         } else {
             self.registers[dst as usize] = self.registers[src as usize];
         }
@@ -559,8 +1072,8 @@ impl Intel8080 {
     fn sta(&mut self) {
         let low_data: u16 = self.memory[(self.pc + 1) as usize] as u16;
         let hi_data: u16 = self.memory[(self.pc + 2) as usize] as u16;
-        let offset: usize = ((hi_data << 8) | low_data) as usize;
-        self.memory[offset] = self.registers[REG_A];
+        let offset: u16 = (hi_data << 8) | low_data;
+        self.write(offset, self.registers[REG_A]);
         self.pc += 3;
     }
 
@@ -571,8 +1084,8 @@ impl Intel8080 {
     fn lda(&mut self) {
         let low_data: u16 = self.memory[(self.pc + 1) as usize] as u16;
         let hi_data: u16 = self.memory[(self.pc + 2) as usize] as u16;
-        let offset: usize = ((hi_data << 8) | low_data) as usize;
-        self.registers[REG_A] = self.memory[offset];
+        let offset: u16 = (hi_data << 8) | low_data;
+        self.registers[REG_A] = self.read(offset);
         self.pc += 3;
     }
 
@@ -585,14 +1098,14 @@ impl Intel8080 {
         let rp = (instruction & 0b0001_0000) >> 4;
         match rp {
             0b0 => {
-                let offset: usize = (((self.registers[REG_B] as u16) << 8)
-                    | (self.registers[REG_C] as u16)) as usize;
-                self.registers[REG_A] = self.memory[offset];
+                let offset: u16 =
+                    ((self.registers[REG_B] as u16) << 8) | (self.registers[REG_C] as u16);
+                self.registers[REG_A] = self.read(offset);
             }
             0b1 => {
-                let offset: usize = (((self.registers[REG_D] as u16) << 8)
-                    | (self.registers[REG_E] as u16)) as usize;
-                self.registers[REG_A] = self.memory[offset];
+                let offset: u16 =
+                    ((self.registers[REG_D] as u16) << 8) | (self.registers[REG_E] as u16);
+                self.registers[REG_A] = self.read(offset);
             }
             _ => {
                 unreachable!("ldax");
@@ -609,9 +1122,9 @@ impl Intel8080 {
         let reg = (instruction & 0b00111000) >> 3;
         let data = self.memory[(self.pc + 1) as usize];
         if reg == M_REF {
-            let offset: usize =
-                (((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16)) as usize;
-            self.memory[offset] = data;
+            let offset: u16 =
+                ((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16);
+            self.write(offset, data);
         } else {
             self.registers[reg as usize] = data;
         }
@@ -656,8 +1169,8 @@ impl Intel8080 {
     /// Description: A return operation is unconditionally performed.
     /// Condition bits affected: None
     fn ret(&mut self) {
-        let hi_addr: u16 = self.memory[self.sp as usize] as u16;
-        let lo_addr: u16 = self.memory[(self.sp + 1) as usize] as u16;
+        let hi_addr: u16 = self.read(self.sp) as u16;
+        let lo_addr: u16 = self.read(self.sp + 1) as u16;
         let addr: u16 = (hi_addr << 8) | lo_addr;
         // println!("returing to addr := {:#06x}", addr);
         self.sp += 2;
@@ -674,8 +1187,8 @@ impl Intel8080 {
         let ret_addr: u16 = self.pc + 3;
         let hi_ret_addr: u8 = ((ret_addr & 0xFF00) >> 8) as u8;
         let lo_ret_addr: u8 = (ret_addr & 0x00FF) as u8;
-        self.memory[(self.sp - 1) as usize] = lo_ret_addr;
-        self.memory[(self.sp - 2) as usize] = hi_ret_addr;
+        self.write(self.sp - 1, lo_ret_addr);
+        self.write(self.sp - 2, hi_ret_addr);
         self.sp -= 2;
         self.pc = addr;
     }
@@ -712,26 +1225,20 @@ impl Intel8080 {
         let rp = (instruction & 0b0011_0000) >> 4;
         match rp {
             0b00 => {
-                self.memory[(self.sp - 1) as usize] = self.registers[REG_B];
-                self.memory[(self.sp - 2) as usize] = self.registers[REG_C];
+                self.write(self.sp - 1, self.registers[REG_B]);
+                self.write(self.sp - 2, self.registers[REG_C]);
             }
             0b01 => {
-                self.memory[(self.sp - 1) as usize] = self.registers[REG_D];
-                self.memory[(self.sp - 2) as usize] = self.registers[REG_E];
+                self.write(self.sp - 1, self.registers[REG_D]);
+                self.write(self.sp - 2, self.registers[REG_E]);
             }
             0b10 => {
-                self.memory[(self.sp - 1) as usize] = self.registers[REG_H];
-                self.memory[(self.sp - 2) as usize] = self.registers[REG_L];
+                self.write(self.sp - 1, self.registers[REG_H]);
+                self.write(self.sp - 2, self.registers[REG_L]);
             }
             0b11 => {
-                self.memory[(self.sp - 1) as usize] = self.registers[REG_A];
-                let mut psw: u8 = 0b0000_0010;
-                psw = psw | (self.cc.cy);
-                psw = psw | (self.cc.p << 2);
-                psw = psw | (self.cc.ac << 4);
-                psw = psw | (self.cc.z << 6);
-                psw = psw | (self.cc.s << 7);
-                self.memory[(self.sp - 2) as usize] = psw;
+                self.write(self.sp - 1, self.registers[REG_A]);
+                self.write(self.sp - 2, self.cc.to_psw());
             }
             _ => {
                 unreachable!("push");
@@ -759,25 +1266,20 @@ impl Intel8080 {
         let rp = (instruction & 0b0011_0000) >> 4;
         match rp {
             0b00 => {
-                self.registers[REG_B] = self.memory[(self.sp + 1) as usize];
-                self.registers[REG_C] = self.memory[self.sp as usize];
+                self.registers[REG_B] = self.read(self.sp + 1);
+                self.registers[REG_C] = self.read(self.sp);
             }
             0b01 => {
-                self.registers[REG_D] = self.memory[(self.sp + 1) as usize];
-                self.registers[REG_E] = self.memory[self.sp as usize];
+                self.registers[REG_D] = self.read(self.sp + 1);
+                self.registers[REG_E] = self.read(self.sp);
             }
             0b10 => {
-                self.registers[REG_H] = self.memory[(self.sp + 1) as usize];
-                self.registers[REG_L] = self.memory[self.sp as usize];
+                self.registers[REG_H] = self.read(self.sp + 1);
+                self.registers[REG_L] = self.read(self.sp);
             }
             0b11 => {
-                self.registers[REG_A] = self.memory[(self.sp + 1) as usize];
-                let psw = self.memory[self.sp as usize];
-                self.cc.cy = psw & 0b0000_0001;
-                self.cc.p = (psw & 0b0000_0100) >> 2;
-                self.cc.ac = (psw & 0b0001_0000) >> 4;
-                self.cc.z = (psw & 0b0100_0000) >> 6;
-                self.cc.s = (psw & 0b1000_0000) >> 7;
+                self.registers[REG_A] = self.read(self.sp + 1);
+                self.cc = ConditionCodes::from_psw(self.read(self.sp));
             }
             _ => {
                 unreachable!("push");
@@ -850,8 +1352,12 @@ impl Intel8080 {
     /// to output device number exp.
     /// Condition bits affected: None
     fn out(&mut self) {
-        // let exp = self.memory[(self.pc + 1) as usize];
         // TODO: It just skips over the data for now :)
+        if !self.observers.is_empty() {
+            let port = self.memory[(self.pc + 1) as usize];
+            let value = self.registers[REG_A];
+            self.observers.each(|o| o.port_out(port, value));
+        }
         self.pc += 2;
     }
 
@@ -906,7 +1412,7 @@ impl Intel8080 {
             M_REF => {
                 let offset: u16 =
                     ((self.registers[REG_H] as u16) << 8) | (self.registers[REG_L] as u16);
-                self.registers[REG_A] ^= self.memory[offset as usize];
+                self.registers[REG_A] ^= self.read(offset);
             }
             _ => {
                 self.registers[REG_A] ^= self.registers[reg as usize];
@@ -917,6 +1423,14 @@ impl Intel8080 {
         self.pc += 1;
     }
 
+    /// Description: The processor is stopped. The registers and
+    /// flags are unaffected.
+    /// Condition bits affected: None
+    fn hlt(&mut self) {
+        self.halted = true;
+        self.pc += 1;
+    }
+
     /// Description: This instruction sets the INTE flip-flop,
     /// enabling the CPU to recognise and respond to interrupts.
     /// Condition bits affected: None
@@ -0,0 +1,307 @@
+// Memory inspection: pattern search, fill and copy, and snapshots to
+// compare memory over time (the usual way to find where a game keeps its
+// score: snapshot, score a point, compare).
+//
+// Search patterns are space-separated tokens, all hexadecimal:
+//
+//   3E 01            bytes (one or two digits)
+//   1234             a 16-bit word (three or four digits), stored low byte
+//                    first the way the 8080 stores it
+//   "SCORE"          the bytes of a string
+//   ??               any byte
+//
+// Changes made here bypass the instruction accessors: watchpoints don't
+// see them and stepping back doesn't undo them.
+use crate::{Intel8080, LoadError, MEMORY_SIZE};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    Empty,
+    InvalidToken(String),
+    UnterminatedString,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::Empty => write!(f, "empty pattern"),
+            PatternError::InvalidToken(token) => write!(f, "invalid pattern '{}'", token),
+            PatternError::UnterminatedString => write!(f, "unterminated string"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// A byte sequence to search for, where None matches any byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Self, PatternError> {
+        let mut bytes = Vec::new();
+        let mut rest = text.trim_start();
+        while !rest.is_empty() {
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').ok_or(PatternError::UnterminatedString)?;
+                bytes.extend(quoted[..end].bytes().map(Some));
+                rest = quoted[end + 1..].trim_start();
+                continue;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let token = &rest[..end];
+            rest = rest[end..].trim_start();
+            if token == "??" {
+                bytes.push(None);
+                continue;
+            }
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_suffix(['h', 'H']))
+                .unwrap_or(token);
+            let invalid = || PatternError::InvalidToken(token.to_string());
+            let value = u16::from_str_radix(digits, 16).map_err(|_| invalid())?;
+            match digits.len() {
+                1 | 2 => bytes.push(Some(value as u8)),
+                3 | 4 => bytes.extend(value.to_le_bytes().map(Some)),
+                _ => return Err(invalid()),
+            }
+        }
+        if bytes.is_empty() {
+            return Err(PatternError::Empty);
+        }
+        Ok(Self { bytes })
+    }
+
+    /// The pattern's bytes, with wildcards as zero. Used for filling.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.iter().map(|b| b.unwrap_or(0)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(data)
+                .all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+}
+
+/// A byte that differs between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteChange {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// A copy of the whole of memory at one moment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    memory: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The bytes in `range` that differ in `newer`.
+    pub fn compare(&self, newer: &Snapshot, range: RangeInclusive<u16>) -> Vec<ByteChange> {
+        range
+            .filter_map(|addr| {
+                let (old, new) = (self.memory[addr as usize], newer.memory[addr as usize]);
+                (old != new).then_some(ByteChange { addr, old, new })
+            })
+            .collect()
+    }
+}
+
+impl Intel8080 {
+    /// Every address in `range` where `pattern` starts and fits entirely
+    /// inside the range. A reversed range finds nothing.
+    pub fn search(&self, range: RangeInclusive<u16>, pattern: &Pattern) -> Vec<u16> {
+        let data = self.memory_range(range.clone());
+        let start = *range.start();
+        (0..data.len())
+            .filter(|&i| pattern.matches(&data[i..]))
+            .map(|i| start.wrapping_add(i as u16))
+            .collect()
+    }
+
+    /// Fill `range` with `bytes`, repeated as often as needed.
+    pub fn fill(&mut self, range: RangeInclusive<u16>, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let start = *range.start() as usize;
+        for (i, addr) in (start..=*range.end() as usize).enumerate() {
+            self.memory[addr] = bytes[i % bytes.len()];
+        }
+    }
+
+    /// Copy `src` to `dest`. Overlapping ranges are copied as if through a
+    /// temporary buffer.
+    pub fn copy(&mut self, src: RangeInclusive<u16>, dest: u16) -> Result<(), LoadError> {
+        if src.is_empty() {
+            return Err(LoadError::ReversedRange {
+                start: *src.start(),
+                end: *src.end(),
+            });
+        }
+        let len = *src.end() as usize + 1 - *src.start() as usize;
+        if dest as usize + len > MEMORY_SIZE {
+            return Err(LoadError::OutOfBounds { addr: dest, len });
+        }
+        let (start, end) = (*src.start() as usize, *src.end() as usize);
+        self.memory.copy_within(start..=end, dest as usize);
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> Vec<Option<u8>> {
+        Pattern::parse(text).unwrap().bytes
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(pattern("3E 1"), [Some(0x3E), Some(0x01)]);
+        assert_eq!(pattern("0x3E 01h"), [Some(0x3E), Some(0x01)]);
+        assert_eq!(pattern("CD ?? ??"), [Some(0xCD), None, None]);
+        // Words are stored low byte first.
+        assert_eq!(
+            pattern("1234 abc"),
+            [Some(0x34), Some(0x12), Some(0xBC), Some(0x0A)]
+        );
+        assert_eq!(
+            pattern("\"HI-LO\" 00"),
+            "HI-LO"
+                .bytes()
+                .map(Some)
+                .chain([Some(0)])
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            pattern("\"a b\"\"c\""),
+            [Some(b'a'), Some(b' '), Some(b'b'), Some(b'c')]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Pattern::parse("  "), Err(PatternError::Empty));
+        assert_eq!(Pattern::parse("\"\""), Err(PatternError::Empty));
+        assert_eq!(
+            Pattern::parse("\"open"),
+            Err(PatternError::UnterminatedString)
+        );
+        assert_eq!(
+            Pattern::parse("12345"),
+            Err(PatternError::InvalidToken("12345".into()))
+        );
+        assert_eq!(
+            Pattern::parse("3E XY"),
+            Err(PatternError::InvalidToken("XY".into()))
+        );
+        assert_eq!(
+            Pattern::parse("?").unwrap_err().to_string(),
+            "invalid pattern '?'"
+        );
+    }
+
+    #[test]
+    fn search_with_wildcards() {
+        let mut cpu = Intel8080::new();
+        cpu.load_at(0x100, &[0xCD, 0x00, 0x02, 0xCD, 0x10, 0x02, 0xCD])
+            .unwrap();
+        let calls = Pattern::parse("CD ?? 02").unwrap();
+        assert_eq!(cpu.search(0x0000..=0xFFFF, &calls), [0x100, 0x103]);
+        // The match has to fit inside the range.
+        assert_eq!(cpu.search(0x0100..=0x0104, &calls), [0x100]);
+        let (start, end) = (0x0200, 0x0100);
+        assert!(cpu.search(start..=end, &calls).is_empty());
+    }
+
+    #[test]
+    fn fill_repeats_the_bytes() {
+        let mut cpu = Intel8080::new();
+        cpu.fill(0x10..=0x14, &[1, 2]);
+        assert_eq!(&cpu.memory()[0x0F..=0x15], [0, 1, 2, 1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn copy_overlapping_ranges() {
+        let mut cpu = Intel8080::new();
+        cpu.load_at(0x10, &[1, 2, 3, 4]).unwrap();
+        // Forwards, onto its own tail.
+        cpu.copy(0x10..=0x13, 0x12).unwrap();
+        assert_eq!(&cpu.memory()[0x10..=0x15], [1, 2, 1, 2, 3, 4]);
+        // Backwards, onto its own head.
+        cpu.copy(0x12..=0x15, 0x11).unwrap();
+        assert_eq!(&cpu.memory()[0x10..=0x15], [1, 1, 2, 3, 4, 4]);
+    }
+
+    #[test]
+    fn copy_errors() {
+        let mut cpu = Intel8080::new();
+        let (start, end) = (0x20, 0x10);
+        assert_eq!(
+            cpu.copy(start..=end, 0x100),
+            Err(LoadError::ReversedRange {
+                start: 0x20,
+                end: 0x10
+            })
+        );
+        assert_eq!(
+            cpu.copy(0x00..=0x0F, 0xFFF8),
+            Err(LoadError::OutOfBounds {
+                addr: 0xFFF8,
+                len: 16
+            })
+        );
+        cpu.copy(0x00..=0x07, 0xFFF8).unwrap();
+    }
+
+    #[test]
+    fn compare_snapshots() {
+        let mut cpu = Intel8080::new();
+        cpu.load_at(0x2000, &[5, 6, 7]).unwrap();
+        let before = cpu.snapshot();
+        cpu.load_at(0x2000, &[5, 9]).unwrap();
+        cpu.load_at(0x3000, &[1]).unwrap();
+        let after = cpu.snapshot();
+        assert_eq!(
+            before.compare(&after, 0x2000..=0x2FFF),
+            [ByteChange {
+                addr: 0x2001,
+                old: 6,
+                new: 9
+            }]
+        );
+        assert_eq!(before.compare(&after, 0x0000..=0xFFFF).len(), 2);
+        assert!(after.compare(&after, 0x0000..=0xFFFF).is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+// Execution observers.
+//
+// An Observer attached to the CPU is told about every instruction fetch,
+// data read and write, OUT and accepted interrupt, so tools outside this
+// crate can watch execution. `port_in` is part of the trait but never fires
+// until IN is emulated. All callbacks default to doing nothing; implement
+// the ones you need. With no observer attached the CPU skips the
+// notifications entirely.
+//
+// Observers are owned by the CPU. To get results out after a run, remove
+// the observer again, or keep the results behind an Rc<RefCell<..>> shared
+// with the observer.
+use crate::{CpuState, Intel8080};
+
+pub trait Observer {
+    /// An instruction is about to execute. `state` is the CPU state before
+    /// it runs, with PC at the instruction.
+    fn fetch(&mut self, _state: &CpuState, _opcode: u8) {}
+
+    /// An instruction read a byte of data (operand and opcode fetches
+    /// excluded).
+    fn read(&mut self, _addr: u16, _value: u8) {}
+
+    /// An instruction wrote a byte of data.
+    fn write(&mut self, _addr: u16, _old: u8, _new: u8) {}
+
+    /// IN read `value` from `port`. IN is not emulated yet, so this isn't
+    /// called until it is.
+    fn port_in(&mut self, _port: u8, _value: u8) {}
+
+    /// OUT wrote `value` to `port`.
+    fn port_out(&mut self, _port: u8, _value: u8) {}
+
+    /// The CPU accepted an interrupt and is about to run RST `vector`;
+    /// `return_addr` is the PC that was pushed.
+    fn interrupt(&mut self, _vector: u8, _return_addr: u16) {}
+}
+
+/// Identifies an attached observer, for removing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(u32);
+
+#[derive(Default)]
+pub(crate) struct Observers {
+    list: Vec<(ObserverId, Box<dyn Observer>)>,
+    next_id: u32,
+}
+
+impl Observers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub(crate) fn each(&mut self, mut f: impl FnMut(&mut dyn Observer)) {
+        for (_, observer) in &mut self.list {
+            f(observer.as_mut());
+        }
+    }
+}
+
+impl Intel8080 {
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) -> ObserverId {
+        let id = ObserverId(self.observers.next_id);
+        self.observers.next_id += 1;
+        self.observers.list.push((id, observer));
+        id
+    }
+
+    pub fn remove_observer(&mut self, id: ObserverId) -> Option<Box<dyn Observer>> {
+        let i = self.observers.list.iter().position(|(i, _)| *i == id)?;
+        Some(self.observers.list.remove(i).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Fetch(u16, u8),
+        Read(u16, u8),
+        Write(u16, u8, u8),
+        Out(u8, u8),
+        Interrupt(u8, u16),
+    }
+
+    struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+    impl Observer for Recorder {
+        fn fetch(&mut self, state: &CpuState, opcode: u8) {
+            self.0.borrow_mut().push(Event::Fetch(state.pc, opcode));
+        }
+
+        fn read(&mut self, addr: u16, value: u8) {
+            self.0.borrow_mut().push(Event::Read(addr, value));
+        }
+
+        fn write(&mut self, addr: u16, old: u8, new: u8) {
+            self.0.borrow_mut().push(Event::Write(addr, old, new));
+        }
+
+        fn port_out(&mut self, port: u8, value: u8) {
+            self.0.borrow_mut().push(Event::Out(port, value));
+        }
+
+        fn interrupt(&mut self, vector: u8, return_addr: u16) {
+            self.0
+                .borrow_mut()
+                .push(Event::Interrupt(vector, return_addr));
+        }
+    }
+
+    #[test]
+    fn events_arrive_in_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = Intel8080::new();
+        let id = cpu.add_observer(Box::new(Recorder(Rc::clone(&events))));
+        let mut cpu = asm::run_on(
+            cpu,
+            "LXI SP,2400h; MVI A,7; STA 2400h; LDA 2400h; OUT 10h; EI; HLT",
+        );
+        assert!(cpu.interrupt(2));
+
+        use Event::*;
+        assert_eq!(
+            *events.borrow(),
+            [
+                Fetch(0x0000, 0x31),
+                Fetch(0x0003, 0x3E),
+                Fetch(0x0005, 0x32),
+                Write(0x2400, 0, 7),
+                Fetch(0x0008, 0x3A),
+                Read(0x2400, 7),
+                Fetch(0x000B, 0xD3),
+                Out(0x10, 7),
+                Fetch(0x000D, 0xFB),
+                Fetch(0x000E, 0x76),
+                Interrupt(2, 0x000F),
+                Write(0x23FF, 0, 0x0F),
+                Write(0x23FE, 0, 0x00),
+            ]
+        );
+
+        assert!(cpu.remove_observer(id).is_some());
+        assert!(cpu.remove_observer(id).is_none());
+        cpu.tick();
+        assert_eq!(events.borrow().len(), 13);
+    }
+}
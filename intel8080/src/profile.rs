@@ -0,0 +1,312 @@
+// Execution profiler.
+//
+// Counts executions and cycles for every address and every opcode, and
+// follows CALL/RST and RET to keep a stack of the subroutines that are
+// running. Each instruction's cycles are charged to the whole stack, which
+// gives inclusive and exclusive time per subroutine and the folded-stack
+// output flame graph tools read:
+//
+//   0100;0116;0140 1234
+//
+// one line per distinct stack, the frames outermost first, followed by the
+// cycles spent with exactly that stack. The outermost frame is the address
+// execution started at.
+use crate::disasm;
+use crate::{Intel8080, StackEffect, MEMORY_SIZE};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl Counter {
+    fn add(&mut self, cycles: u64) {
+        self.count += 1;
+        self.cycles += cycles;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subroutine {
+    pub addr: u16,
+    pub calls: u64,
+    /// Cycles spent in the subroutine and everything it called.
+    pub inclusive: u64,
+    /// Cycles spent in the subroutine's own instructions.
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    addrs: Vec<Counter>,
+    opcodes: [Counter; 256],
+    total: Counter,
+    calls: HashMap<u16, u64>,
+    stack: Vec<u16>,
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            addrs: vec![Counter::default(); MEMORY_SIZE],
+            opcodes: [Counter::default(); 256],
+            total: Counter::default(),
+            calls: HashMap::new(),
+            stack: Vec::new(),
+            folded: HashMap::new(),
+        }
+    }
+
+    /// Forget everything recorded so far.
+    pub fn clear(&mut self) {
+        self.addrs.fill(Counter::default());
+        self.opcodes = [Counter::default(); 256];
+        self.total = Counter::default();
+        self.calls.clear();
+        self.stack.clear();
+        self.folded.clear();
+    }
+
+    pub(crate) fn record(&mut self, pc: u16, op: u8, cycles: u64, effect: StackEffect, next: u16) {
+        self.addrs[pc as usize].add(cycles);
+        self.opcodes[op as usize].add(cycles);
+        self.total.add(cycles);
+
+        if self.stack.is_empty() {
+            self.stack.push(pc);
+        }
+        match self.folded.get_mut(self.stack.as_slice()) {
+            Some(total) => *total += cycles,
+            None => {
+                self.folded.insert(self.stack.clone(), cycles);
+            }
+        }
+        match effect {
+            StackEffect::Call => {
+                self.stack.push(next);
+                *self.calls.entry(next).or_default() += 1;
+            }
+            // A return with nothing left to return from (the program
+            // unwound past where profiling began) leaves the root in place.
+            StackEffect::Return if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// An interrupt ran RST to `target`: open a frame for the handler, as
+    /// a CALL would, and charge the RST's cycles to it.
+    pub(crate) fn enter(&mut self, target: u16, cycles: u64) {
+        self.opcodes[0xc7 | target as usize].add(cycles);
+        self.total.add(cycles);
+        self.stack.push(target);
+        *self.calls.entry(target).or_default() += 1;
+        *self.folded.entry(self.stack.clone()).or_default() += cycles;
+    }
+
+    /// Instructions executed and cycles spent in total.
+    pub fn total(&self) -> Counter {
+        self.total
+    }
+
+    pub fn address(&self, addr: u16) -> Counter {
+        self.addrs[addr as usize]
+    }
+
+    pub fn opcode(&self, op: u8) -> Counter {
+        self.opcodes[op as usize]
+    }
+
+    /// Every subroutine that was entered, most inclusive time first. The
+    /// root frame is included with zero calls.
+    pub fn subroutines(&self) -> Vec<Subroutine> {
+        let mut subs: HashMap<u16, Subroutine> = HashMap::new();
+        for (stack, &cycles) in &self.folded {
+            for (i, &addr) in stack.iter().enumerate() {
+                let sub = subs.entry(addr).or_insert_with(|| Subroutine {
+                    addr,
+                    calls: self.calls.get(&addr).copied().unwrap_or(0),
+                    ..Subroutine::default()
+                });
+                // Count recursive frames once.
+                if !stack[..i].contains(&addr) {
+                    sub.inclusive += cycles;
+                }
+                if i == stack.len() - 1 {
+                    sub.exclusive += cycles;
+                }
+            }
+        }
+        let mut subs: Vec<Subroutine> = subs.into_values().collect();
+        subs.sort_by_key(|s| (std::cmp::Reverse(s.inclusive), s.addr));
+        subs
+    }
+
+    pub fn report(&self, mem: &[u8], top: usize) -> String {
+        self.report_with(mem, top, &|_| None)
+    }
+
+    /// A text report of the `top` hottest addresses, every subroutine and
+    /// the instruction mix. `name` supplies labels for addresses.
+    pub fn report_with(
+        &self,
+        mem: &[u8],
+        top: usize,
+        name: &dyn Fn(u16) -> Option<String>,
+    ) -> String {
+        let total = self.total.cycles;
+        let label = |addr: u16| match name(addr) {
+            Some(name) => format!("{:04X} {}", addr, name),
+            None => format!("{:04X}", addr),
+        };
+        let mut out = format!(
+            "{} instructions, {} cycles\n",
+            self.total.count, self.total.cycles
+        );
+
+        out.push_str("\nHOT SPOTS\n  ADDR        COUNT       CYCLES       %  INSTRUCTION\n");
+        let mut hot: Vec<(u16, Counter)> = (0..=u16::MAX)
+            .map(|addr| (addr, self.address(addr)))
+            .filter(|(_, c)| c.count > 0)
+            .collect();
+        hot.sort_by_key(|(addr, c)| (std::cmp::Reverse(c.cycles), *addr));
+        for (addr, c) in hot.iter().take(top) {
+            let instr = disasm::decode(mem, *addr);
+            out.push_str(&format!(
+                "  {:04X}  {:>11}  {:>11}  {:>6.2}  {}\n",
+                addr,
+                c.count,
+                c.cycles,
+                percent(c.cycles, total),
+                instr.format_with(name)
+            ));
+        }
+
+        out.push_str("\nSUBROUTINES\n        CALLS    INCLUSIVE    EXCLUSIVE   %INCL  ADDR\n");
+        for sub in self.subroutines() {
+            out.push_str(&format!(
+                "  {:>11}  {:>11}  {:>11}  {:>6.2}  {}\n",
+                sub.calls,
+                sub.inclusive,
+                sub.exclusive,
+                percent(sub.inclusive, total),
+                label(sub.addr)
+            ));
+        }
+
+        out.push_str("\nINSTRUCTION MIX\n  OP  MNEMONIC        COUNT       CYCLES       %\n");
+        let mut mix: Vec<(u8, Counter)> = (0..=u8::MAX)
+            .map(|op| (op, self.opcode(op)))
+            .filter(|(_, c)| c.count > 0)
+            .collect();
+        mix.sort_by_key(|(op, c)| (std::cmp::Reverse(c.count), *op));
+        for (op, c) in mix {
+            let instr = disasm::decode(&[op, 0, 0], 0);
+            out.push_str(&format!(
+                "  {:02X}  {:<8}  {:>11}  {:>11}  {:>6.2}\n",
+                op,
+                instr.mnemonic,
+                c.count,
+                c.cycles,
+                percent(c.cycles, total)
+            ));
+        }
+        out
+    }
+
+    pub fn folded(&self) -> String {
+        self.folded_with(&|_| None)
+    }
+
+    /// The folded-stack listing, sorted by stack. Frames are named by
+    /// `name` where it knows the address, and by hex address otherwise.
+    pub fn folded_with(&self, name: &dyn Fn(u16) -> Option<String>) -> String {
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .map(|(stack, cycles)| {
+                let frames: Vec<String> = stack
+                    .iter()
+                    .map(|&addr| name(addr).unwrap_or_else(|| format!("{:04X}", addr)))
+                    .collect();
+                format!("{} {}\n", frames.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+impl Intel8080 {
+    pub fn attach_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn detach_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn interrupt_opens_a_frame() {
+        let source = "
+            ORG 8
+            RET
+            ORG 100h
+            LXI SP,2000h
+            EI
+            CALL 110h
+            HLT
+            ORG 110h
+            MVI A,1
+            MVI B,2
+            RET
+        ";
+        let assembly = asm::assemble(source).unwrap();
+        let mut cpu = Intel8080::new();
+        cpu.load_image(&assembly.image).unwrap();
+        cpu.set_pc(0x100);
+        cpu.attach_profiler(Profiler::new());
+        while cpu.state().pc != 0x112 {
+            cpu.tick();
+        }
+        assert!(cpu.interrupt(1));
+        while !cpu.is_halted() {
+            cpu.tick();
+        }
+        let profiler = cpu.detach_profiler().unwrap();
+        assert_eq!(
+            profiler.folded(),
+            "0100 38\n0100;0110 24\n0100;0110;0008 21\n"
+        );
+        assert_eq!(profiler.total().cycles, 83);
+        assert_eq!(profiler.opcode(0xcf).cycles, 11);
+    }
+}
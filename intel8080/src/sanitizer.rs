@@ -0,0 +1,390 @@
+// Memory access checker.
+//
+// The 8080 has no memory protection, so a stray pointer or a runaway stack
+// corrupts memory without a trace. With a Sanitizer attached the CPU
+// reports:
+//
+// - data reads of bytes that were never written or loaded,
+// - writes into declared ROM regions (the write still happens),
+// - SP leaving the declared stack window,
+// - execution outside the declared code regions.
+//
+// Each problem is reported once per address (once per excursion for the
+// stack and for execution outside the code regions), at the instruction
+// where it first happened. The return address an interrupt pushes is
+// reported against the instruction the interrupt preempted.
+//
+// Bytes loaded with `load_at`, `load_segments` or `load_image` while the
+// sanitizer is attached count as initialised; changes made through
+// `memory_mut` do not.
+use crate::{Image, Intel8080, MEMORY_SIZE};
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::{self, Discriminant};
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Violation {
+    UninitializedRead { pc: u16, addr: u16 },
+    RomWrite { pc: u16, addr: u16, value: u8 },
+    StackOutOfBounds { pc: u16, sp: u16 },
+    ExecuteNonCode { pc: u16 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::UninitializedRead { pc, addr } => {
+                write!(
+                    f,
+                    "{:04X}: read of uninitialised memory at {:04X}",
+                    pc, addr
+                )
+            }
+            Violation::RomWrite { pc, addr, value } => {
+                write!(f, "{:04X}: wrote {:02X} to ROM at {:04X}", pc, value, addr)
+            }
+            Violation::StackOutOfBounds { pc, sp } => {
+                write!(f, "{:04X}: SP={:04X} is outside the stack", pc, sp)
+            }
+            Violation::ExecuteNonCode { pc } => {
+                write!(f, "{:04X}: executing outside the code regions", pc)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    initialized: Vec<bool>,
+    rom: Vec<RangeInclusive<u16>>,
+    code: Vec<RangeInclusive<u16>>,
+    stack: Option<RangeInclusive<u16>>,
+    // The instruction being executed, for reports.
+    pc: u16,
+    pc_outside: bool,
+    sp_outside: bool,
+    violations: Vec<Violation>,
+    // Kind and address of everything reported, so each is reported once.
+    seen: HashSet<(Discriminant<Violation>, u16)>,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sanitizer {
+    /// A checker with no memory initialised and no regions declared.
+    pub fn new() -> Self {
+        Self {
+            initialized: vec![false; MEMORY_SIZE],
+            rom: Vec::new(),
+            code: Vec::new(),
+            stack: None,
+            pc: 0,
+            pc_outside: false,
+            sp_outside: false,
+            violations: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// A checker for a loaded image: its segments count as initialised and
+    /// as code.
+    pub fn for_image(image: &Image) -> Self {
+        let mut sanitizer = Self::new();
+        for seg in image.segments.iter().filter(|s| !s.data.is_empty()) {
+            let range = seg.addr..=(seg.end() - 1) as u16;
+            sanitizer.mark_initialized(range.clone());
+            sanitizer.add_code(range);
+        }
+        sanitizer
+    }
+
+    /// Count `range` as written. A reversed range marks nothing.
+    pub fn mark_initialized(&mut self, range: RangeInclusive<u16>) {
+        if range.is_empty() {
+            return;
+        }
+        self.initialized[*range.start() as usize..=*range.end() as usize].fill(true);
+    }
+
+    pub fn add_rom(&mut self, range: RangeInclusive<u16>) {
+        self.rom.push(range);
+    }
+
+    /// Declare a region execution may happen in. With none declared,
+    /// execution is not checked.
+    pub fn add_code(&mut self, range: RangeInclusive<u16>) {
+        self.code.push(range);
+    }
+
+    pub fn set_stack(&mut self, range: RangeInclusive<u16>) {
+        self.stack = Some(range);
+    }
+
+    pub fn with_rom(mut self, range: RangeInclusive<u16>) -> Self {
+        self.add_rom(range);
+        self
+    }
+
+    pub fn with_code(mut self, range: RangeInclusive<u16>) -> Self {
+        self.add_code(range);
+        self
+    }
+
+    pub fn with_stack(mut self, range: RangeInclusive<u16>) -> Self {
+        self.set_stack(range);
+        self
+    }
+
+    /// Everything reported so far, in the order it happened.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn is_initialized(&self, addr: u16) -> bool {
+        self.initialized[addr as usize]
+    }
+
+    /// Forget what has been initialised and reported, keeping the declared
+    /// regions.
+    pub fn clear(&mut self) {
+        self.initialized.fill(false);
+        self.pc = 0;
+        self.pc_outside = false;
+        self.sp_outside = false;
+        self.violations.clear();
+        self.seen.clear();
+    }
+
+    fn report(&mut self, addr: u16, violation: Violation) {
+        if self.seen.insert((mem::discriminant(&violation), addr)) {
+            self.violations.push(violation);
+        }
+    }
+
+    pub(crate) fn begin(&mut self, pc: u16) {
+        self.pc = pc;
+        if self.code.is_empty() {
+            return;
+        }
+        let outside = !self.code.iter().any(|r| r.contains(&pc));
+        if outside && !self.pc_outside {
+            self.report(pc, Violation::ExecuteNonCode { pc });
+        }
+        self.pc_outside = outside;
+    }
+
+    // An interrupt is about to push `ret_addr`, the instruction it
+    // preempted.
+    pub(crate) fn begin_interrupt(&mut self, ret_addr: u16) {
+        self.pc = ret_addr;
+    }
+
+    pub(crate) fn check_read(&mut self, addr: u16) {
+        if !self.initialized[addr as usize] {
+            let pc = self.pc;
+            self.report(addr, Violation::UninitializedRead { pc, addr });
+        }
+    }
+
+    pub(crate) fn check_write(&mut self, addr: u16, value: u8) {
+        self.initialized[addr as usize] = true;
+        if self.rom.iter().any(|r| r.contains(&addr)) {
+            let pc = self.pc;
+            self.report(addr, Violation::RomWrite { pc, addr, value });
+        }
+    }
+
+    // Called after each instruction with the SP it left.
+    pub(crate) fn check_sp(&mut self, sp: u16) {
+        let Some(stack) = &self.stack else {
+            return;
+        };
+        let outside = !stack.contains(&sp);
+        if outside && !self.sp_outside {
+            self.violations
+                .push(Violation::StackOutOfBounds { pc: self.pc, sp });
+        }
+        self.sp_outside = outside;
+    }
+}
+
+impl Intel8080 {
+    pub fn attach_sanitizer(&mut self, sanitizer: Sanitizer) {
+        self.sanitizer = Some(sanitizer);
+    }
+
+    pub fn detach_sanitizer(&mut self) -> Option<Sanitizer> {
+        self.sanitizer.take()
+    }
+
+    pub fn sanitizer(&self) -> Option<&Sanitizer> {
+        self.sanitizer.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn check(sanitizer: Sanitizer, source: &str) -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.attach_sanitizer(sanitizer);
+        asm::run_on(cpu, source)
+    }
+
+    fn violations(cpu: &Intel8080) -> &[Violation] {
+        cpu.sanitizer().unwrap().violations()
+    }
+
+    #[test]
+    fn uninitialized_read() {
+        let cpu = check(
+            Sanitizer::new(),
+            "LXI H,2400h; MVI B,2; LOOP: MOV A,M; DCR B; JNZ LOOP; LDA 0; HLT",
+        );
+        // Reported once although read twice; the loaded program counts as
+        // initialised.
+        assert_eq!(
+            violations(&cpu),
+            &[Violation::UninitializedRead {
+                pc: 0x0005,
+                addr: 0x2400
+            }]
+        );
+        assert_eq!(
+            violations(&cpu)[0].to_string(),
+            "0005: read of uninitialised memory at 2400"
+        );
+    }
+
+    #[test]
+    fn written_memory_is_initialized() {
+        let cpu = check(Sanitizer::new(), "MVI A,1; STA 2400h; LDA 2400h; HLT");
+        assert!(violations(&cpu).is_empty());
+        assert!(cpu.sanitizer().unwrap().is_initialized(0x2400));
+    }
+
+    #[test]
+    fn rom_write() {
+        let cpu = check(
+            Sanitizer::new().with_rom(0x2000..=0x20FF),
+            "MVI A,5; STA 2000h; STA 2100h; HLT",
+        );
+        assert_eq!(
+            violations(&cpu),
+            &[Violation::RomWrite {
+                pc: 0x0002,
+                addr: 0x2000,
+                value: 5
+            }]
+        );
+        // The write still happens.
+        assert_eq!(cpu.memory()[0x2000], 5);
+    }
+
+    #[test]
+    fn stack_out_of_bounds() {
+        let cpu = check(
+            Sanitizer::new().with_stack(0x23FE..=0x2400),
+            "LXI SP,2400h; PUSH B; PUSH B; PUSH B; POP B; POP B; POP B; PUSH B; PUSH B; HLT",
+        );
+        // One report per excursion.
+        assert_eq!(
+            violations(&cpu),
+            &[
+                Violation::StackOutOfBounds {
+                    pc: 0x0004,
+                    sp: 0x23FC
+                },
+                Violation::StackOutOfBounds {
+                    pc: 0x000A,
+                    sp: 0x23FC
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn execute_non_code() {
+        let cpu = check(
+            Sanitizer::new().with_code(0x0000..=0x0002),
+            "JMP 0006h; NOP; NOP; NOP; NOP; HLT",
+        );
+        assert_eq!(
+            violations(&cpu),
+            &[Violation::ExecuteNonCode { pc: 0x0006 }]
+        );
+
+        let image = asm::assemble("\tJMP 0100h\n\tORG 100h\n\tNOP\n\tHLT\n")
+            .unwrap()
+            .image;
+        let mut cpu = Intel8080::new();
+        cpu.load_image(&image).unwrap();
+        cpu.attach_sanitizer(Sanitizer::for_image(&image));
+        while !cpu.is_halted() {
+            cpu.tick();
+        }
+        assert!(cpu.sanitizer().unwrap().violations().is_empty());
+    }
+
+    #[test]
+    fn reversed_range_marks_nothing() {
+        let mut sanitizer = Sanitizer::new();
+        let (start, end) = (0x10, 0x0F);
+        sanitizer.mark_initialized(start..=end);
+        assert!(!sanitizer.is_initialized(0x10));
+        assert!(!sanitizer.is_initialized(0x0F));
+    }
+
+    #[test]
+    fn loading_marks_bytes_initialized() {
+        let mut cpu = Intel8080::new();
+        cpu.attach_sanitizer(Sanitizer::new());
+        cpu.load_at(0x2400, &[1, 2]).unwrap();
+        cpu.load_at(0x3000, &[]).unwrap();
+        let sanitizer = cpu.sanitizer().unwrap();
+        assert!(sanitizer.is_initialized(0x2400));
+        assert!(sanitizer.is_initialized(0x2401));
+        assert!(!sanitizer.is_initialized(0x2402));
+        assert!(!sanitizer.is_initialized(0x3000));
+    }
+
+    #[test]
+    fn interrupt_is_reported_at_the_preempted_instruction() {
+        let mut cpu = check(
+            Sanitizer::new()
+                .with_rom(0x23F0..=0x23FF)
+                .with_stack(0x2400..=0x2400),
+            "LXI SP,2400h; EI; HLT",
+        );
+        assert!(violations(&cpu).is_empty());
+        // HLT left PC on the next instruction, which is where the
+        // interrupt returns to.
+        assert!(cpu.interrupt(1));
+        assert_eq!(
+            violations(&cpu),
+            &[
+                Violation::RomWrite {
+                    pc: 0x0005,
+                    addr: 0x23FF,
+                    value: 0x05
+                },
+                Violation::RomWrite {
+                    pc: 0x0005,
+                    addr: 0x23FE,
+                    value: 0x00
+                },
+                Violation::StackOutOfBounds {
+                    pc: 0x0005,
+                    sp: 0x23FE
+                },
+            ]
+        );
+    }
+}
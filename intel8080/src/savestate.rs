@@ -0,0 +1,504 @@
+// Save-state file format
+//
+//   offset  size  field
+//   0       4     magic "I80S"
+//   4       2     format version (little endian)
+//   6       2     number of sections
+//   8       ...   sections, each: 4-byte tag, u32 payload length, payload
+//   end-4   4     CRC-32 of every byte before it
+//
+// The "CPU " and "MEM " sections are always present. Any other tag belongs
+// to an attached device; readers keep sections they don't understand so a
+// state written by a newer front-end survives a load/save round trip.
+// Sections may also grow: readers only look at the prefix they know about.
+use crate::{CpuState, Intel8080, MEMORY_SIZE};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"I80S";
+pub const FORMAT_VERSION: u16 = 1;
+
+pub const CPU_TAG: [u8; 4] = *b"CPU ";
+pub const MEM_TAG: [u8; 4] = *b"MEM ";
+
+// Registers and flags. States written before the cycle count was added
+// stop here and load with the count at zero.
+const CPU_SECTION_LEN: usize = 14;
+const CYCLES_LEN: usize = 8;
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 4;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    Truncated,
+    /// Bytes left over after the last section.
+    TrailingData(usize),
+    MissingSection([u8; 4]),
+    CorruptSection([u8; 4]),
+    ReservedSection([u8; 4]),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::Io(err) => write!(f, "i/o error: {}", err),
+            SaveStateError::BadMagic => write!(f, "not an intel8080 save state"),
+            SaveStateError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "save state version {} is newer than {}",
+                    v, FORMAT_VERSION
+                )
+            }
+            SaveStateError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch: expected {:#010X}, found {:#010X}",
+                expected, found
+            ),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::TrailingData(len) => {
+                write!(f, "{} unexpected bytes after the last section", len)
+            }
+            SaveStateError::MissingSection(tag) => {
+                write!(f, "missing section {:?}", String::from_utf8_lossy(tag))
+            }
+            SaveStateError::CorruptSection(tag) => {
+                write!(f, "corrupt section {:?}", String::from_utf8_lossy(tag))
+            }
+            SaveStateError::ReservedSection(tag) => {
+                write!(f, "section {:?} is reserved", String::from_utf8_lossy(tag))
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> Self {
+        SaveStateError::Io(err)
+    }
+}
+
+/// A complete machine state: CPU registers and flags, the cycle count, the
+/// 64 KiB memory image and any extra sections contributed by attached
+/// devices.
+///
+/// The memory image is private so it is always exactly 64 KiB: states come
+/// from `capture` or `from_bytes`, both of which guarantee it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaveState {
+    pub cpu: CpuState,
+    pub cycles: u64,
+    memory: Vec<u8>,
+    sections: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl SaveState {
+    pub fn capture(cpu: &Intel8080) -> Self {
+        Self {
+            cpu: cpu.state(),
+            cycles: cpu.cycles(),
+            memory: cpu.memory().to_vec(),
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Put the state into `cpu`. Attached tools forget what they recorded,
+    /// as on reset, since it belongs to another timeline; all of memory
+    /// counts as initialised for a sanitizer.
+    pub fn restore(&self, cpu: &mut Intel8080) {
+        cpu.set_state(&self.cpu);
+        cpu.cycles = self.cycles;
+        cpu.memory_mut().copy_from_slice(&self.memory);
+        cpu.clear_tools();
+        if let Some(sanitizer) = &mut cpu.sanitizer {
+            sanitizer.mark_initialized(0..=0xFFFF);
+        }
+    }
+
+    /// Store the payload of a device section, replacing any previous
+    /// payload with the same tag. The CPU and memory tags are reserved.
+    pub fn set_section(&mut self, tag: [u8; 4], data: Vec<u8>) -> Result<(), SaveStateError> {
+        if tag == CPU_TAG || tag == MEM_TAG {
+            return Err(SaveStateError::ReservedSection(tag));
+        }
+        match self.sections.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, payload)) => *payload = data,
+            None => self.sections.push((tag, data)),
+        }
+        Ok(())
+    }
+
+    pub fn section(&self, tag: [u8; 4]) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&((self.sections.len() + 2) as u16).to_le_bytes());
+        push_section(&mut out, CPU_TAG, &encode_cpu(&self.cpu, self.cycles));
+        push_section(&mut out, MEM_TAG, &pack_bits(&self.memory));
+        for (tag, data) in &self.sections {
+            push_section(&mut out, *tag, data);
+        }
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SaveStateError> {
+        if bytes.len() < HEADER_LEN + CRC_LEN {
+            return Err(SaveStateError::Truncated);
+        }
+        if &bytes[..4] != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let (body, trailer) = bytes.split_at(bytes.len() - CRC_LEN);
+        let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let found = crc32(body);
+        if expected != found {
+            return Err(SaveStateError::ChecksumMismatch { expected, found });
+        }
+        let version = u16::from_le_bytes([body[4], body[5]]);
+        if version > FORMAT_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let count = u16::from_le_bytes([body[6], body[7]]);
+
+        let mut cpu = None;
+        let mut memory = None;
+        let mut sections = Vec::new();
+        let mut rest = &body[HEADER_LEN..];
+        for _ in 0..count {
+            if rest.len() < 8 {
+                return Err(SaveStateError::Truncated);
+            }
+            let tag = [rest[0], rest[1], rest[2], rest[3]];
+            let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            rest = &rest[8..];
+            if rest.len() < len {
+                return Err(SaveStateError::Truncated);
+            }
+            let (data, tail) = rest.split_at(len);
+            rest = tail;
+            match tag {
+                CPU_TAG => cpu = Some((decode_cpu(data)?, decode_cycles(data))),
+                MEM_TAG => memory = Some(unpack_bits(data)?),
+                _ => sections.push((tag, data.to_vec())),
+            }
+        }
+        if !rest.is_empty() {
+            return Err(SaveStateError::TrailingData(rest.len()));
+        }
+
+        let (cpu, cycles) = cpu.ok_or(SaveStateError::MissingSection(CPU_TAG))?;
+        Ok(Self {
+            cpu,
+            cycles,
+            memory: memory.ok_or(SaveStateError::MissingSection(MEM_TAG))?,
+            sections,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), SaveStateError> {
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, SaveStateError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Intel8080 {
+    pub fn save_state(&self) -> Vec<u8> {
+        SaveState::capture(self).to_bytes()
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SaveStateError> {
+        SaveState::from_bytes(bytes)?.restore(self);
+        Ok(())
+    }
+}
+
+fn push_section(out: &mut Vec<u8>, tag: [u8; 4], data: &[u8]) {
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn encode_cpu(cpu: &CpuState, cycles: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(CPU_SECTION_LEN + CYCLES_LEN);
+    out.extend_from_slice(&[cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l]);
+    out.extend_from_slice(&cpu.sp.to_le_bytes());
+    out.extend_from_slice(&cpu.pc.to_le_bytes());
+    out.push(cpu.flags);
+    out.push(cpu.interrupts_enable as u8);
+    out.push(cpu.halted as u8);
+    out.extend_from_slice(&cycles.to_le_bytes());
+    out
+}
+
+fn decode_cpu(data: &[u8]) -> Result<CpuState, SaveStateError> {
+    if data.len() < CPU_SECTION_LEN {
+        return Err(SaveStateError::CorruptSection(CPU_TAG));
+    }
+    Ok(CpuState {
+        a: data[0],
+        b: data[1],
+        c: data[2],
+        d: data[3],
+        e: data[4],
+        h: data[5],
+        l: data[6],
+        sp: u16::from_le_bytes([data[7], data[8]]),
+        pc: u16::from_le_bytes([data[9], data[10]]),
+        flags: data[11],
+        interrupts_enable: data[12] != 0,
+        halted: data[13] != 0,
+    })
+}
+
+fn decode_cycles(data: &[u8]) -> u64 {
+    data.get(CPU_SECTION_LEN..CPU_SECTION_LEN + CYCLES_LEN)
+        .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// PackBits run-length encoding: a header byte n in 0..=127 is followed by
+// n + 1 literal bytes, n in 129..=255 means repeat the next byte 257 - n
+// times. Memory images are mostly zero so this keeps the files small.
+fn pack_bits(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 128 && data[i + run] == data[i] {
+            run += 1;
+        }
+        if run >= 2 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && i - start < 128 {
+            if i + 1 < data.len() && data[i] == data[i + 1] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+fn unpack_bits(data: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+    let mut out = Vec::with_capacity(MEMORY_SIZE);
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i] as usize;
+        i += 1;
+        if header < 128 {
+            let end = i + header + 1;
+            if end > data.len() {
+                return Err(SaveStateError::CorruptSection(MEM_TAG));
+            }
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        } else if header > 128 {
+            let byte = *data.get(i).ok_or(SaveStateError::CorruptSection(MEM_TAG))?;
+            out.resize(out.len() + 257 - header, byte);
+            i += 1;
+        }
+    }
+    if out.len() != MEMORY_SIZE {
+        return Err(SaveStateError::CorruptSection(MEM_TAG));
+    }
+    Ok(out)
+}
+
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Intel8080 {
+        let mut cpu = crate::asm::run("LXI SP,2000h; MVI A,5; STA 0300h; HLT");
+        cpu.load_at(0x8000, &[0x55; 300]).unwrap();
+        cpu
+    }
+
+    // Replace the trailing CRC after editing the body.
+    fn reseal(bytes: &mut Vec<u8>) {
+        bytes.truncate(bytes.len() - CRC_LEN);
+        let crc = crc32(bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip_is_byte_exact() {
+        let cpu = sample();
+        let bytes = cpu.save_state();
+        let state = SaveState::from_bytes(&bytes).unwrap();
+        assert_eq!(state, SaveState::capture(&cpu));
+        assert_eq!(state.to_bytes(), bytes);
+
+        let mut other = Intel8080::new();
+        other.load_state(&bytes).unwrap();
+        assert_eq!(other.state(), cpu.state());
+        assert_eq!(other.cycles(), cpu.cycles());
+        assert_eq!(other.memory(), cpu.memory());
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut bytes = sample().save_state();
+        bytes[HEADER_LEN + 8] ^= 1;
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn bad_magic_and_version() {
+        let mut bytes = sample().save_state();
+        bytes[0] = b'X';
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::BadMagic)
+        ));
+
+        let mut bytes = sample().save_state();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        reseal(&mut bytes);
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn truncated_and_trailing_input() {
+        assert!(matches!(
+            SaveState::from_bytes(b"I80S"),
+            Err(SaveStateError::Truncated)
+        ));
+
+        // One more section claimed than present.
+        let mut bytes = sample().save_state();
+        bytes[6] += 1;
+        reseal(&mut bytes);
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::Truncated)
+        ));
+
+        let mut bytes = sample().save_state();
+        let at = bytes.len() - CRC_LEN;
+        bytes.splice(at..at, [1, 2, 3]);
+        reseal(&mut bytes);
+        assert!(matches!(
+            SaveState::from_bytes(&bytes),
+            Err(SaveStateError::TrailingData(3))
+        ));
+    }
+
+    #[test]
+    fn unknown_sections_are_kept() {
+        let mut state = SaveState::capture(&sample());
+        state.set_section(*b"SND ", vec![1, 2, 3]).unwrap();
+        state.set_section(*b"SND ", vec![4]).unwrap();
+        let read = SaveState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(read.section(*b"SND "), Some(&[4][..]));
+        assert_eq!(read.section(*b"VID "), None);
+        assert_eq!(read.to_bytes(), state.to_bytes());
+    }
+
+    #[test]
+    fn reserved_tags_are_rejected() {
+        let mut state = SaveState::capture(&sample());
+        for tag in [CPU_TAG, MEM_TAG] {
+            assert!(matches!(
+                state.set_section(tag, Vec::new()),
+                Err(SaveStateError::ReservedSection(t)) if t == tag
+            ));
+        }
+    }
+
+    #[test]
+    fn restore_clears_attached_tools() {
+        let bytes = Intel8080::new().save_state();
+        let mut cpu = Intel8080::new();
+        cpu.load(&[0x31, 0x00, 0x20, 0xcd, 0x00, 0x01]).unwrap();
+        cpu.record_history(10);
+        cpu.track_calls();
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.call_stack().unwrap().depth(), 1);
+        assert!(!cpu.history().unwrap().is_empty());
+        cpu.load_state(&bytes).unwrap();
+        assert!(cpu.history().unwrap().is_empty());
+        assert!(!cpu.step_back());
+        assert_eq!(cpu.call_stack().unwrap().depth(), 0);
+    }
+
+    #[test]
+    fn pack_bits_edge_cases() {
+        let same = vec![7u8; MEMORY_SIZE];
+        let packed = pack_bits(&same);
+        assert_eq!(packed.len(), MEMORY_SIZE / 128 * 2);
+        assert_eq!(unpack_bits(&packed).unwrap(), same);
+
+        // Runs longer than 128 split, literals around them survive.
+        let mut data = vec![0u8; MEMORY_SIZE];
+        data[..3].copy_from_slice(&[1, 2, 3]);
+        data[3..303].fill(9);
+        for (i, b) in data[400..700].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(unpack_bits(&pack_bits(&data)).unwrap(), data);
+
+        assert!(matches!(
+            unpack_bits(&[0x81]),
+            Err(SaveStateError::CorruptSection(MEM_TAG))
+        ));
+        assert!(matches!(
+            unpack_bits(&pack_bits(&[0; 10])),
+            Err(SaveStateError::CorruptSection(MEM_TAG))
+        ));
+    }
+}
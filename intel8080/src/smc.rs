@@ -0,0 +1,167 @@
+// Self-modifying code detection.
+//
+// Remembers every byte that has been executed as part of an instruction
+// and reports instructions that later write to one of them: patched jump
+// targets, overwritten immediates, code copied over code. Each write is
+// recorded, and passed to the hook if one is set, as it happens. Writes
+// that store the byte already there change nothing and are not reported.
+use crate::{disasm, Intel8080, MEMORY_SIZE};
+use std::fmt;
+
+/// A write to a byte that had already been executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeWrite {
+    pub addr: u16,
+    /// The instruction that did the write.
+    pub pc: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for CodeWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}: code at {:04X} changed from {:02X} to {:02X}",
+            self.pc, self.addr, self.old, self.new
+        )
+    }
+}
+
+type Hook = Box<dyn FnMut(&CodeWrite)>;
+
+pub struct SmcDetector {
+    executed: Vec<bool>,
+    writes: Vec<CodeWrite>,
+    hook: Option<Hook>,
+    // The instruction being executed.
+    pc: u16,
+}
+
+impl Default for SmcDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmcDetector {
+    pub fn new() -> Self {
+        Self {
+            executed: vec![false; MEMORY_SIZE],
+            writes: Vec::new(),
+            hook: None,
+            pc: 0,
+        }
+    }
+
+    /// Call `hook` for every code write as it happens.
+    pub fn with_hook(mut self, hook: impl FnMut(&CodeWrite) + 'static) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Every code write so far, oldest first.
+    pub fn writes(&self) -> &[CodeWrite] {
+        &self.writes
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.executed[addr as usize]
+    }
+
+    /// Forget what has been executed and written, keeping the hook.
+    pub fn clear(&mut self) {
+        self.executed.fill(false);
+        self.writes.clear();
+        self.pc = 0;
+    }
+
+    pub(crate) fn begin(&mut self, pc: u16, op: u8) {
+        self.pc = pc;
+        for i in 0..disasm::instruction_length(op) {
+            self.executed[pc.wrapping_add(i as u16) as usize] = true;
+        }
+    }
+
+    pub(crate) fn check_write(&mut self, addr: u16, old: u8, new: u8) {
+        if old == new || !self.executed[addr as usize] {
+            return;
+        }
+        let write = CodeWrite {
+            addr,
+            pc: self.pc,
+            old,
+            new,
+        };
+        if let Some(hook) = &mut self.hook {
+            hook(&write);
+        }
+        self.writes.push(write);
+    }
+}
+
+impl Intel8080 {
+    pub fn attach_smc_detector(&mut self, detector: SmcDetector) {
+        self.smc = Some(detector);
+    }
+
+    pub fn detach_smc_detector(&mut self) -> Option<SmcDetector> {
+        self.smc.take()
+    }
+
+    pub fn smc_detector(&self) -> Option<&SmcDetector> {
+        self.smc.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Patches the immediate of MVI C at 0002 on every pass: with 9 over 7
+    // the first time, and with the 9 already there the second.
+    const PATCH: &str =
+        "MVI B,2; LOOP: MVI C,7; MVI A,9; STA 0003h; STA 2400h; DCR B; JNZ LOOP; HLT";
+
+    #[test]
+    fn write_to_executed_code_is_reported() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hook_seen = Rc::clone(&seen);
+        let mut cpu = Intel8080::new();
+        cpu.attach_smc_detector(
+            SmcDetector::new().with_hook(move |w| hook_seen.borrow_mut().push(*w)),
+        );
+        let cpu = asm::run_on(cpu, PATCH);
+
+        let expected = CodeWrite {
+            addr: 0x0003,
+            pc: 0x0006,
+            old: 7,
+            new: 9,
+        };
+        let detector = cpu.smc_detector().unwrap();
+        assert_eq!(detector.writes(), &[expected]);
+        assert_eq!(*seen.borrow(), [expected]);
+        assert_eq!(
+            expected.to_string(),
+            "0006: code at 0003 changed from 07 to 09"
+        );
+        assert_eq!(cpu.state().c, 9);
+    }
+
+    #[test]
+    fn unchanged_and_data_writes_are_not_reported() {
+        let mut cpu = Intel8080::new();
+        cpu.attach_smc_detector(SmcDetector::new());
+        let cpu = asm::run_on(cpu, "MVI A,3Eh; STA 0000h; STA 2400h; HLT");
+        let detector = cpu.smc_detector().unwrap();
+        // The MVI opcode was stored over itself, and 2400h never ran.
+        assert!(detector.writes().is_empty());
+        assert!(detector.is_executed(0x0000));
+        assert!(detector.is_executed(0x0001));
+        assert!(!detector.is_executed(0x2400));
+    }
+}
@@ -0,0 +1,348 @@
+// Showing CPU state.
+//
+// Three layouts are available through `Intel8080::format_state`, and
+// through the formatting traits:
+//
+//   Compact  PC=0100 SP=2000 A=00 BC=0000 DE=0000 HL=0000 F=02 [-----]
+//            (`{}` on CpuState; on Intel8080 followed by the instruction
+//            at PC)
+//   Table    the register, flag and PC/SP tables (`{:#}` on Intel8080)
+//   Json     {"pc":256,"sp":8192,"a":0,...}
+//
+// JSON numbers are plain decimal so any parser reads them.
+use crate::{CpuState, Intel8080};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFormat {
+    Compact,
+    Table,
+    Json,
+}
+
+impl StateFormat {
+    /// Look a format up by its lower-case name: compact, table or json.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "compact" => Some(StateFormat::Compact),
+            "table" => Some(StateFormat::Table),
+            "json" => Some(StateFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The condition bits, in the PSW layout PUSH PSW stores: S Z 0 AC 0 P 1 CY.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags(pub u8);
+
+impl Flags {
+    pub fn sign(&self) -> bool {
+        self.0 & 0b1000_0000 != 0
+    }
+
+    pub fn zero(&self) -> bool {
+        self.0 & 0b0100_0000 != 0
+    }
+
+    pub fn aux_carry(&self) -> bool {
+        self.0 & 0b0001_0000 != 0
+    }
+
+    pub fn parity(&self) -> bool {
+        self.0 & 0b0000_0100 != 0
+    }
+
+    pub fn carry(&self) -> bool {
+        self.0 & 0b0000_0001 != 0
+    }
+}
+
+/// "SZAPC", with a '-' for each clear bit.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = [
+            (self.sign(), 'S'),
+            (self.zero(), 'Z'),
+            (self.aux_carry(), 'A'),
+            (self.parity(), 'P'),
+            (self.carry(), 'C'),
+        ];
+        for (set, name) in bits {
+            write!(f, "{}", if set { name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Flags")
+            .field("s", &self.sign())
+            .field("z", &self.zero())
+            .field("ac", &self.aux_carry())
+            .field("p", &self.parity())
+            .field("cy", &self.carry())
+            .finish()
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC={:04X} SP={:04X} A={:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} F={:02X} [{}]",
+            self.pc,
+            self.sp,
+            self.a,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.flags,
+            Flags(self.flags)
+        )?;
+        if self.interrupts_enable {
+            write!(f, " EI")?;
+        }
+        if self.halted {
+            write!(f, " HALTED")?;
+        }
+        Ok(())
+    }
+}
+
+impl CpuState {
+    pub fn to_json(&self) -> String {
+        json(self, None)
+    }
+}
+
+// The state as a JSON object, with a "cycles" field when given a count.
+fn json(s: &CpuState, cycles: Option<u64>) -> String {
+    let flags = Flags(s.flags);
+    let mut out = format!(
+        concat!(
+            "{{\"pc\":{},\"sp\":{},\"a\":{},\"b\":{},\"c\":{},\"d\":{},\"e\":{},",
+            "\"h\":{},\"l\":{},\"flags\":{},\"s\":{},\"z\":{},\"ac\":{},\"p\":{},",
+            "\"cy\":{},\"interrupts_enable\":{},\"halted\":{}"
+        ),
+        s.pc,
+        s.sp,
+        s.a,
+        s.b,
+        s.c,
+        s.d,
+        s.e,
+        s.h,
+        s.l,
+        s.flags,
+        flags.sign(),
+        flags.zero(),
+        flags.aux_carry(),
+        flags.parity(),
+        flags.carry(),
+        s.interrupts_enable,
+        s.halted
+    );
+    if let Some(cycles) = cycles {
+        out.push_str(&format!(",\"cycles\":{}", cycles));
+    }
+    out.push('}');
+    out
+}
+
+impl Intel8080 {
+    pub fn flags(&self) -> Flags {
+        Flags(self.state().flags)
+    }
+
+    pub fn format_state(&self, format: StateFormat) -> String {
+        match format {
+            StateFormat::Compact => self.to_string(),
+            StateFormat::Table => format!("{:#}", self),
+            StateFormat::Json => json(&self.state(), Some(self.cycles())),
+        }
+    }
+
+    fn write_table(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.state();
+        let flags = Flags(s.flags);
+        writeln!(
+            f,
+            "opcode: {:#04X} ({})",
+            self.memory()[s.pc as usize],
+            self.disassemble(s.pc)
+        )?;
+        writeln!(f, "        CPU Misc. Field State")?;
+        writeln!(f, "-------------------------------------------")?;
+        writeln!(f, "FIELD |DEC\t|HEX\t|BIN               |")?;
+        writeln!(f, "-------------------------------------------|")?;
+        writeln!(f, "PC    |{}\t|{:#06X}\t|{:#018b}|", s.pc, s.pc, s.pc)?;
+        writeln!(f, "SP    |{}\t|{:#06X}\t|{:#018b}|", s.sp, s.sp, s.sp)?;
+        writeln!(f, "-------------------------------------------|")?;
+        writeln!(f, "                                           |")?;
+        writeln!(f, "        CPU Register State                 |")?;
+        writeln!(f, "-------------------------------------------|")?;
+        writeln!(f, "REGISTER |DEC\t|HEX\t|BIN               |")?;
+        writeln!(f, "-------------------------------------------|")?;
+        let registers = [
+            ('A', s.a),
+            ('B', s.b),
+            ('C', s.c),
+            ('D', s.d),
+            ('E', s.e),
+            ('H', s.h),
+            ('L', s.l),
+        ];
+        for (name, value) in registers {
+            writeln!(
+                f,
+                "{}        |{}\t|{:#04X}\t|{:#010b}        |",
+                name, value, value, value
+            )?;
+        }
+        writeln!(f, "-------------------------------------------|")?;
+        writeln!(f, "                                           |")?;
+        writeln!(f, "        CPU FLAG State                     |")?;
+        writeln!(f, "-------------------------------------------|")?;
+        writeln!(f, "        FLAG         |       VALUE         |")?;
+        writeln!(f, "-------------------------------------------|")?;
+        let bits = [
+            ("CARRY", flags.carry()),
+            ("PARITY", flags.parity()),
+            ("AUX-CARRY", flags.aux_carry()),
+            ("ZERO", flags.zero()),
+            ("SIGN", flags.sign()),
+        ];
+        for (name, set) in bits {
+            writeln!(f, "  {:<19}| {:#04X}\t           |", name, set as u8)?;
+        }
+        write!(f, "-------------------------------------------")
+    }
+}
+
+/// One line: the registers, then the instruction at PC. The alternate form
+/// (`{:#}`) is the full table.
+impl fmt::Display for Intel8080 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            return self.write_table(f);
+        }
+        let s = self.state();
+        write!(f, "{}  {}", s, self.disassemble(s.pc))
+    }
+}
+
+impl fmt::Debug for Intel8080 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.state();
+        f.debug_struct("Intel8080")
+            .field("pc", &format_args!("{:#06X}", s.pc))
+            .field("sp", &format_args!("{:#06X}", s.sp))
+            .field("a", &format_args!("{:#04X}", s.a))
+            .field("b", &format_args!("{:#04X}", s.b))
+            .field("c", &format_args!("{:#04X}", s.c))
+            .field("d", &format_args!("{:#04X}", s.d))
+            .field("e", &format_args!("{:#04X}", s.e))
+            .field("h", &format_args!("{:#04X}", s.h))
+            .field("l", &format_args!("{:#04X}", s.l))
+            .field("flags", &Flags(s.flags))
+            .field("interrupts_enable", &s.interrupts_enable)
+            .field("halted", &s.halted)
+            .field("cycles", &self.cycles())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LXI B,1234h at 0100 has run (10 cycles); LXI SP,2000h at 0103 is next.
+    fn cpu() -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        cpu.load_at(0x100, &[0x01, 0x34, 0x12, 0x31, 0x00, 0x20])
+            .unwrap();
+        cpu.set_pc(0x100);
+        cpu.tick();
+        let mut state = cpu.state();
+        state.a = 0xA5;
+        (state.h, state.l) = (0xBE, 0xEF);
+        state.flags = 0b1000_0111;
+        state.interrupts_enable = true;
+        cpu.set_state(&state);
+        cpu
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(Flags(0b1101_0111).to_string(), "SZAPC");
+        assert_eq!(Flags(0b0000_0010).to_string(), "-----");
+        assert_eq!(Flags(0b0100_0001).to_string(), "-Z--C");
+        assert_eq!(
+            format!("{:?}", Flags(0b0001_0000)),
+            "Flags { s: false, z: false, ac: true, p: false, cy: false }"
+        );
+    }
+
+    #[test]
+    fn compact() {
+        let cpu = cpu();
+        let line = "PC=0103 SP=0000 A=A5 BC=1234 DE=0000 HL=BEEF F=87 [S--PC] EI";
+        assert_eq!(cpu.state().to_string(), line);
+        assert_eq!(cpu.to_string(), format!("{}  LXI SP,2000h", line));
+        assert_eq!(cpu.format_state(StateFormat::Compact), cpu.to_string());
+
+        let mut state = cpu.state();
+        state.interrupts_enable = false;
+        state.halted = true;
+        assert!(state.to_string().ends_with("[S--PC] HALTED"));
+    }
+
+    #[test]
+    fn table() {
+        let cpu = cpu();
+        let table = cpu.format_state(StateFormat::Table);
+        assert_eq!(table, format!("{:#}", cpu));
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "opcode: 0x31 (LXI SP,2000h)");
+        assert!(lines.contains(&"PC    |259\t|0x0103\t|0b0000000100000011|"));
+        assert!(lines.contains(&"A        |165\t|0xA5\t|0b10100101        |"));
+        assert!(lines.contains(&"  CARRY              | 0x01\t           |"));
+        assert!(lines.contains(&"  ZERO               | 0x00\t           |"));
+        assert_eq!(
+            lines.last(),
+            Some(&"-------------------------------------------")
+        );
+    }
+
+    #[test]
+    fn json() {
+        let cpu = cpu();
+        let fields = concat!(
+            "{\"pc\":259,\"sp\":0,\"a\":165,\"b\":18,\"c\":52,\"d\":0,\"e\":0,",
+            "\"h\":190,\"l\":239,\"flags\":135,\"s\":true,\"z\":false,\"ac\":false,",
+            "\"p\":true,\"cy\":true,\"interrupts_enable\":true,\"halted\":false"
+        );
+        // Only the CPU knows the cycle count.
+        assert_eq!(cpu.state().to_json(), format!("{}}}", fields));
+        assert_eq!(
+            cpu.format_state(StateFormat::Json),
+            format!("{},\"cycles\":10}}", fields)
+        );
+    }
+
+    #[test]
+    fn format_names() {
+        assert_eq!(
+            StateFormat::from_name("compact"),
+            Some(StateFormat::Compact)
+        );
+        assert_eq!(StateFormat::from_name("table"), Some(StateFormat::Table));
+        assert_eq!(StateFormat::from_name("json"), Some(StateFormat::Json));
+        assert_eq!(StateFormat::from_name("JSON"), None);
+    }
+}
@@ -0,0 +1,311 @@
+// Symbol tables.
+//
+// Symbols come from the assembler, or from a file in one of these forms,
+// which `SymbolTable::parse` tells apart line by line:
+//
+//   0100 START  0103 LOOP       .SYM files: hex address and name pairs,
+//                               several to a line, ended by ^Z if present
+//   START = 0100h               one name = value per line, with the
+//                               assembler's number syntax
+//
+// An assembler listing can be read too; only its SYMBOLS section is used.
+// Comments start with ';' or '#'.
+//
+// `label` turns an address into `NAME` or `NAME+offset` using the closest
+// symbol at or below it. Offsets past 9 are written in hex with an h
+// suffix, so the result reads the same to the assembler and the debugger.
+use crate::asm::expr;
+use crate::disasm;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// An address further than this past the closest symbol is left unnamed,
+// rather than shown as an offset from some unrelated label.
+const MAX_OFFSET: u16 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    names: BTreeMap<String, u16>,
+    // The first name defined for each address.
+    addrs: BTreeMap<u16, String>,
+}
+
+impl From<&BTreeMap<String, u16>> for SymbolTable {
+    fn from(symbols: &BTreeMap<String, u16>) -> Self {
+        let mut table = Self::new();
+        for (name, addr) in symbols {
+            table.insert(name, *addr);
+        }
+        table
+    }
+}
+
+// A .SYM address: hex digits with an optional h suffix.
+fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text.strip_suffix(['h', 'H']).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Define `name`, replacing an earlier definition of the same name.
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.names.insert(name.to_string(), addr) {
+            if self.addrs.get(&old).is_some_and(|n| n == name) {
+                self.addrs.remove(&old);
+            }
+        }
+        self.addrs.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// Add every symbol of `other`.
+    pub fn merge(&mut self, other: &SymbolTable) {
+        for (name, addr) in &other.names {
+            self.insert(name, *addr);
+        }
+    }
+
+    /// Symbols in name order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.names.iter().map(|(name, addr)| (name.as_str(), *addr))
+    }
+
+    /// The value of `name`, matched case-insensitively if there is no
+    /// exact match.
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.names.get(name).copied().or_else(|| {
+            self.names
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, addr)| *addr)
+        })
+    }
+
+    /// The symbol defined exactly at `addr`.
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.addrs.get(&addr).map(String::as_str)
+    }
+
+    /// `addr` as `NAME` or `NAME+offset`, or None if no symbol is close
+    /// enough below it.
+    pub fn label(&self, addr: u16) -> Option<String> {
+        let (base, name) = self.addrs.range(..=addr).next_back()?;
+        match addr - base {
+            0 => Some(name.clone()),
+            offset @ 1..=9 => Some(format!("{}+{}", name, offset)),
+            offset if offset <= MAX_OFFSET => {
+                Some(format!("{}+{}", name, disasm::hex8(offset as u8)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolve `NAME`, `NAME+n` or `NAME-n`, with `n` in assembler number
+    /// syntax.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        self.resolve_with(text, &expr::parse_number)
+    }
+
+    /// Resolve `NAME`, `NAME+n` or `NAME-n`, with `n` read by `number`.
+    pub fn resolve_with(&self, text: &str, number: &dyn Fn(&str) -> Option<u16>) -> Option<u16> {
+        match text.find(['+', '-']) {
+            Some(i) => {
+                let base = self.lookup(&text[..i])?;
+                let offset = number(&text[i + 1..])?;
+                Some(if text.as_bytes()[i] == b'+' {
+                    base.wrapping_add(offset)
+                } else {
+                    base.wrapping_sub(offset)
+                })
+            }
+            None => self.lookup(text),
+        }
+    }
+
+    /// Read a symbol file in any of the formats above.
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let text = text.split('\x1a').next().unwrap_or("");
+        // In an assembler listing, skip to the symbol table.
+        let mut lines: Vec<(usize, &str)> = text.lines().enumerate().collect();
+        if let Some(i) = lines.iter().position(|(_, l)| l.trim() == "SYMBOLS") {
+            lines.drain(..=i);
+        }
+
+        let mut table = Self::new();
+        for (i, line) in lines {
+            let err = |message: String| SymbolError {
+                line: i + 1,
+                message,
+            };
+            let line = line.split([';', '#']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((name, value)) = line.split_once('=') {
+                let (name, value) = (name.trim(), value.trim());
+                let addr = expr::parse_number(value)
+                    .ok_or_else(|| err(format!("invalid value '{}'", value)))?;
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(err(format!("invalid symbol name '{}'", name)));
+                }
+                table.insert(name, addr);
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            for pair in tokens.chunks(2) {
+                let addr = parse_hex(pair[0])
+                    .ok_or_else(|| err(format!("invalid address '{}'", pair[0])))?;
+                let name = pair
+                    .get(1)
+                    .ok_or_else(|| err(format!("missing name after {}", pair[0])))?;
+                table.insert(name, addr);
+            }
+        }
+        Ok(table)
+    }
+
+    /// The table as a .SYM file, one symbol per line in address order.
+    pub fn to_sym(&self) -> String {
+        let mut symbols: Vec<(u16, &str)> = self.iter().map(|(n, a)| (a, n)).collect();
+        symbols.sort();
+        symbols
+            .iter()
+            .map(|(addr, name)| format!("{:04X} {}\n", addr, name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn table(pairs: &[(&str, u16)]) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for (name, addr) in pairs {
+            table.insert(name, *addr);
+        }
+        table
+    }
+
+    #[test]
+    fn parse_sym_file() {
+        let parsed = SymbolTable::parse("0100 START  0103 LOOP\n0200h BUFFER\n\x1a0300 JUNK\n");
+        assert_eq!(
+            parsed,
+            Ok(table(&[
+                ("START", 0x100),
+                ("LOOP", 0x103),
+                ("BUFFER", 0x200)
+            ]))
+        );
+        assert_eq!(
+            SymbolTable::parse("0100 START\n0103"),
+            Err(SymbolError {
+                line: 2,
+                message: "missing name after 0103".into()
+            })
+        );
+        assert_eq!(
+            SymbolTable::parse("XYZ START").unwrap_err().message,
+            "invalid address 'XYZ'"
+        );
+    }
+
+    #[test]
+    fn parse_assignments() {
+        let text = "; generated\nSTART = 0100h\n# decimal\nCOUNT = 16\nMASK = 1010b ; bits\n";
+        assert_eq!(
+            SymbolTable::parse(text),
+            Ok(table(&[("START", 0x100), ("COUNT", 16), ("MASK", 10)]))
+        );
+        let err = SymbolTable::parse("START = 0100h\nEND = nowhere\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid value 'nowhere'");
+        assert_eq!(
+            SymbolTable::parse("TWO WORDS = 1").unwrap_err().message,
+            "invalid symbol name 'TWO WORDS'"
+        );
+    }
+
+    #[test]
+    fn parse_listing() {
+        let assembly =
+            asm::assemble("\tORG 100h\nSTART:\tNOP\nLOOP:\tJMP LOOP\nCR\tEQU 0Dh\n").unwrap();
+        let parsed = SymbolTable::parse(&assembly.listing()).unwrap();
+        assert_eq!(parsed, SymbolTable::from(&assembly.symbols));
+        assert_eq!(parsed.lookup("LOOP"), Some(0x101));
+        assert_eq!(parsed.len(), 3);
+    }
+
+    #[test]
+    fn labels_stop_at_max_offset() {
+        let symbols = table(&[("START", 0x100), ("NEXT", 0x300)]);
+        assert_eq!(symbols.label(0x100).as_deref(), Some("START"));
+        assert_eq!(symbols.label(0x109).as_deref(), Some("START+9"));
+        assert_eq!(symbols.label(0x10A).as_deref(), Some("START+0Ah"));
+        assert_eq!(
+            symbols.label(0x100 + MAX_OFFSET).as_deref(),
+            Some("START+0FFh")
+        );
+        assert_eq!(symbols.label(0x100 + MAX_OFFSET + 1), None);
+        assert_eq!(symbols.label(0xFF), None);
+        assert_eq!(symbols.label(0x301).as_deref(), Some("NEXT+1"));
+    }
+
+    #[test]
+    fn labels_read_back_through_resolve() {
+        let symbols = table(&[("START", 0x100)]);
+        for addr in [0x100, 0x105, 0x10A, 0x1FF] {
+            let label = symbols.label(addr).unwrap();
+            assert_eq!(symbols.resolve(&label), Some(addr), "{}", label);
+        }
+    }
+
+    #[test]
+    fn resolve_with_offset() {
+        let symbols = table(&[("START", 0x100), ("Zero", 0)]);
+        assert_eq!(symbols.resolve("START"), Some(0x100));
+        assert_eq!(symbols.resolve("start+10"), Some(0x10A));
+        assert_eq!(symbols.resolve("START+10h"), Some(0x110));
+        assert_eq!(symbols.resolve("START-1"), Some(0xFF));
+        assert_eq!(symbols.resolve("ZERO-1"), Some(0xFFFF));
+        assert_eq!(symbols.resolve("START+x"), None);
+        assert_eq!(symbols.resolve("OTHER+1"), None);
+        let hex = |text: &str| u16::from_str_radix(text, 16).ok();
+        assert_eq!(symbols.resolve_with("START+10", &hex), Some(0x110));
+    }
+
+    #[test]
+    fn first_name_at_an_address_wins() {
+        let mut symbols = table(&[("MAIN", 0x100), ("START", 0x100)]);
+        assert_eq!(symbols.name_at(0x100), Some("MAIN"));
+        symbols.insert("MAIN", 0x200);
+        assert_eq!(symbols.name_at(0x200), Some("MAIN"));
+        assert_eq!(symbols.to_sym(), "0100 START\n0200 MAIN\n");
+    }
+}
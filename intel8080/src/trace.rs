@@ -0,0 +1,367 @@
+// One-line-per-instruction execution traces.
+//
+// A line is written before each instruction executes and shows the state
+// the instruction starts from. Three layouts are available:
+//
+//   Native     0100  31 00 20  LXI SP,2000h      A=00 BC=0000 DE=0000 HL=0000 SP=0000 F=02 [-----] CYC=0
+//   Registers  PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0	(31 00 20 00)
+//   PcMem      A:00 F:02 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0100 PCMEM:31,00,20,00
+//
+// Registers is the layout printed by several reference 8080 emulators when
+// running the CP/M test ROMs; PcMem is the "doctor" layout used by a number
+// of trace-comparison tools. Both are easy to diff against those logs.
+//
+// Given symbols, Native lines name the address operands.
+use crate::symbols::SymbolTable;
+use crate::{CpuState, Intel8080};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Native,
+    Registers,
+    PcMem,
+}
+
+impl TraceFormat {
+    /// Look a format up by its lower-case name: native, registers or pcmem.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "native" => Some(TraceFormat::Native),
+            "registers" => Some(TraceFormat::Registers),
+            "pcmem" => Some(TraceFormat::PcMem),
+            _ => None,
+        }
+    }
+}
+
+/// The trace line for the instruction at PC, without a newline.
+pub fn format_line(cpu: &Intel8080, format: TraceFormat) -> String {
+    format_line_with(cpu, format, &|_| None)
+}
+
+/// `format_line`, with address operands named by `name`.
+pub fn format_line_with(
+    cpu: &Intel8080,
+    format: TraceFormat,
+    name: &dyn Fn(u16) -> Option<String>,
+) -> String {
+    let s = cpu.state();
+    let pcmem: Vec<u8> = (0..4)
+        .map(|i| cpu.memory()[s.pc.wrapping_add(i) as usize])
+        .collect();
+    match format {
+        TraceFormat::Native => format!(
+            "{:<34}A={:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} F={:02X} [{}] CYC={}",
+            crate::disasm::format_line_with(&cpu.disassemble(s.pc), name),
+            s.a,
+            s.b,
+            s.c,
+            s.d,
+            s.e,
+            s.h,
+            s.l,
+            s.sp,
+            s.flags,
+            s.flag_letters(),
+            cpu.cycles()
+        ),
+        TraceFormat::Registers => format!(
+            "PC: {:04X}, AF: {:02X}{:02X}, BC: {:02X}{:02X}, DE: {:02X}{:02X}, HL: {:02X}{:02X}, SP: {:04X}, CYC: {}\t({:02X} {:02X} {:02X} {:02X})",
+            s.pc,
+            s.a,
+            s.flags,
+            s.b,
+            s.c,
+            s.d,
+            s.e,
+            s.h,
+            s.l,
+            s.sp,
+            cpu.cycles(),
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3]
+        ),
+        TraceFormat::PcMem => format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            s.a,
+            s.flags,
+            s.b,
+            s.c,
+            s.d,
+            s.e,
+            s.h,
+            s.l,
+            s.sp,
+            s.pc,
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3]
+        ),
+    }
+}
+
+/// The registers of one trace line, as read back from a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub flags: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+}
+
+// Only S, Z, AC, P and CY are compared; emulators disagree about the
+// unused flag bits.
+const FLAG_MASK: u8 = 0b1101_0101;
+
+impl TraceRecord {
+    pub fn from_state(s: &CpuState) -> Self {
+        Self {
+            pc: s.pc,
+            sp: s.sp,
+            a: s.a,
+            flags: s.flags,
+            b: s.b,
+            c: s.c,
+            d: s.d,
+            e: s.e,
+            h: s.h,
+            l: s.l,
+        }
+    }
+
+    /// Read a line in any of the three trace formats. Keys are matched
+    /// case-insensitively and pairs (AF, BC, ...) may stand in for single
+    /// registers, so similar logs from other emulators parse too. Returns
+    /// None for lines without a full register set, such as headers.
+    pub fn parse(line: &str) -> Option<Self> {
+        let tokens: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|t| !t.is_empty())
+            .collect();
+        let mut fields: Vec<(String, &str)> = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token.split_once([':', '=']) {
+                Some((key, "")) => {
+                    if let Some(value) = tokens.get(i + 1) {
+                        fields.push((key.to_ascii_uppercase(), value));
+                    }
+                }
+                Some((key, value)) => fields.push((key.to_ascii_uppercase(), value)),
+                None => {}
+            }
+        }
+        let get = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .and_then(|(_, v)| u16::from_str_radix(v, 16).ok())
+        };
+        let byte = |single: &str, pair: &str, high: bool| {
+            get(single).map(|v| v as u8).or_else(|| {
+                let [hi, lo] = get(pair)?.to_be_bytes();
+                Some(if high { hi } else { lo })
+            })
+        };
+        // Native lines start with the address instead of a PC field.
+        let pc = get("PC").or_else(|| {
+            let first = tokens.first()?;
+            (first.len() == 4)
+                .then(|| u16::from_str_radix(first, 16).ok())
+                .flatten()
+        })?;
+        Some(Self {
+            pc,
+            sp: get("SP")?,
+            a: byte("A", "AF", true)?,
+            flags: byte("F", "AF", false)?,
+            b: byte("B", "BC", true)?,
+            c: byte("C", "BC", false)?,
+            d: byte("D", "DE", true)?,
+            e: byte("E", "DE", false)?,
+            h: byte("H", "HL", true)?,
+            l: byte("L", "HL", false)?,
+        })
+    }
+
+    /// The registers that differ between the two records, with this
+    /// record's value first.
+    pub fn differences(&self, other: &Self) -> Vec<(&'static str, u16, u16)> {
+        let pairs = [
+            ("PC", self.pc, other.pc),
+            ("SP", self.sp, other.sp),
+            ("A", self.a as u16, other.a as u16),
+            (
+                "F",
+                (self.flags & FLAG_MASK) as u16,
+                (other.flags & FLAG_MASK) as u16,
+            ),
+            ("B", self.b as u16, other.b as u16),
+            ("C", self.c as u16, other.c as u16),
+            ("D", self.d as u16, other.d as u16),
+            ("E", self.e as u16, other.e as u16),
+            ("H", self.h as u16, other.h as u16),
+            ("L", self.l as u16, other.l as u16),
+        ];
+        pairs.into_iter().filter(|(_, a, b)| a != b).collect()
+    }
+}
+
+/// Writes a trace line for every instruction the CPU executes while the
+/// tracer is attached. With address ranges added, only instructions inside
+/// one of them are traced.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    ranges: Vec<RangeInclusive<u16>>,
+    symbols: SymbolTable,
+    // The first write error; tracing stops once one happens.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: TraceFormat) -> Self {
+        Self {
+            out: Box::new(BufWriter::new(out)),
+            format,
+            ranges: Vec::new(),
+            symbols: SymbolTable::new(),
+            error: None,
+        }
+    }
+
+    pub fn add_range(&mut self, range: RangeInclusive<u16>) {
+        self.ranges.push(range);
+    }
+
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.add_range(range);
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub(crate) fn trace(&mut self, cpu: &Intel8080) {
+        if self.error.is_some() {
+            return;
+        }
+        let pc = cpu.state().pc;
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|r| r.contains(&pc)) {
+            return;
+        }
+        if let Err(err) = writeln!(
+            self.out,
+            "{}",
+            format_line_with(cpu, self.format, &|addr| self.symbols.label(addr))
+        ) {
+            self.error = Some(err);
+        }
+    }
+
+    /// Flush the output and report the first error hit while tracing.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.flush()
+    }
+}
+
+impl Intel8080 {
+    pub fn attach_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn detach_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> Intel8080 {
+        let mut cpu = Intel8080::new();
+        let mut state = cpu.state();
+        state.pc = 0x0100;
+        state.sp = 0x2000;
+        state.a = 0x12;
+        state.flags = 0x57;
+        (state.b, state.c) = (0x34, 0x56);
+        (state.d, state.e) = (0x78, 0x9A);
+        (state.h, state.l) = (0xBC, 0xDE);
+        cpu.set_state(&state);
+        cpu.load_at(0x0100, &[0x31, 0x00, 0x24]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn every_format_parses_back() {
+        let cpu = cpu();
+        let expected = TraceRecord::from_state(&cpu.state());
+        for format in [
+            TraceFormat::Native,
+            TraceFormat::Registers,
+            TraceFormat::PcMem,
+        ] {
+            let line = format_line(&cpu, format);
+            assert_eq!(TraceRecord::parse(&line), Some(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn lines_from_other_emulators() {
+        let expected = TraceRecord::from_state(&cpu().state());
+        let lines = [
+            "0100  31 00 24  LXI SP,2400h  A=12 BC=3456 DE=789A HL=BCDE SP=2000 F=57 [SZAPC] CYC=0",
+            "PC: 0100, AF: 1257, BC: 3456, DE: 789A, HL: BCDE, SP: 2000, CYC: 17\t(31 00 24 00)",
+            "A:12 F:57 B:34 C:56 D:78 E:9A H:BC L:DE SP:2000 PC:0100 PCMEM:31,00,24,00",
+            "pc=0100 af=1257 bc=3456 de=789a hl=bcde sp=2000",
+        ];
+        for line in lines {
+            assert_eq!(TraceRecord::parse(line), Some(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn incomplete_lines_are_skipped() {
+        assert_eq!(TraceRecord::parse(""), None);
+        assert_eq!(TraceRecord::parse("8080 instruction exerciser"), None);
+        assert_eq!(TraceRecord::parse("PC: 0100, AF: 0002, BC: 0000"), None);
+        assert_eq!(
+            TraceRecord::parse("A:00 F:02 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000"),
+            None
+        );
+    }
+
+    #[test]
+    fn differences_ignore_unused_flag_bits() {
+        let record = TraceRecord::from_state(&cpu().state());
+        let mut other = record;
+        assert!(record.differences(&other).is_empty());
+        other.flags ^= 0b0010_1010;
+        assert!(record.differences(&other).is_empty());
+
+        other.flags ^= 0b0000_0001;
+        other.pc = 0x0103;
+        other.h = 0;
+        assert_eq!(
+            record.differences(&other),
+            [("PC", 0x0100, 0x0103), ("F", 0x55, 0x54), ("H", 0xBC, 0)]
+        );
+    }
+}